@@ -0,0 +1,247 @@
+use crate::{
+    optimize::Op,
+    run::{CellWidth, Eof, MemoryConfig, Tape},
+};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult, Write},
+    str::FromStr,
+};
+
+/// Number of cells to allocate when the tape isn't `Tape::Fixed`
+const DEFAULT_TAPE_LEN: usize = 65536;
+
+/// Translate a program into standalone source for the given target
+///
+/// Cells always wrap, since neither target has a cheap way to report overflow.
+/// Growable tapes are given `DEFAULT_TAPE_LEN` cells, with `Tape::Both` starting in the middle.
+/// Procedures are looked up by cell value at runtime, so they can't be compiled.
+pub fn generate(
+    program: &[Op],
+    config: &MemoryConfig,
+    target: Target,
+) -> Result<String, GenerateError> {
+    if let Some(op) = program.iter().find(|op| is_procedure(op)) {
+        return Err(GenerateError::Unsupported(op.clone(), target));
+    }
+
+    let mut output = String::new();
+
+    match target {
+        Target::C => c(program, config, &mut output),
+        Target::Qbe => qbe(program, config, &mut output),
+    }
+    .expect("writing to a String should never fail");
+
+    Ok(output)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GenerateError {
+    Unsupported(Op, Target),
+}
+
+impl Display for GenerateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Unsupported(op, target) => write!(f, "{} can't be compiled to {}", op, target),
+        }
+    }
+}
+
+impl Error for GenerateError {}
+
+fn is_procedure(op: &Op) -> bool {
+    matches!(op, Op::Define(_) | Op::Return | Op::Call)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+    C,
+    Qbe,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "c" => Self::C,
+            "qbe" => Self::Qbe,
+            _ => return Err(format!("unknown target {}, expected c or qbe", s)),
+        })
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::C => write!(f, "c"),
+            Self::Qbe => write!(f, "qbe"),
+        }
+    }
+}
+
+impl Target {
+    /// File extension for generated source
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::C => "c",
+            Self::Qbe => "ssa",
+        }
+    }
+}
+
+/// Number of cells on the tape and the cell the pointer starts at
+fn tape(config: &MemoryConfig) -> (usize, usize) {
+    match config.tape {
+        Tape::Fixed(len) => (len, 0),
+        Tape::Right => (DEFAULT_TAPE_LEN, 0),
+        Tape::Both => (DEFAULT_TAPE_LEN, DEFAULT_TAPE_LEN / 2),
+    }
+}
+
+fn c(program: &[Op], config: &MemoryConfig, f: &mut String) -> FmtResult {
+    let cell = match config.width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    };
+    let (len, start) = tape(config);
+
+    writeln!(f, "#include <stdint.h>")?;
+    writeln!(f, "#include <stdio.h>")?;
+    writeln!(f)?;
+    writeln!(f, "static {} tape[{}];", cell, len)?;
+    writeln!(f)?;
+    writeln!(f, "int main(void) {{")?;
+    writeln!(f, "    {} *p = tape + {};", cell, start)?;
+    writeln!(f, "    int c;")?;
+
+    let mut depth = 1;
+    for op in program {
+        match op {
+            Op::Break => continue,
+            Op::JumpIfNonZero(_) => depth -= 1,
+            _ => {}
+        }
+
+        write!(f, "{:1$}", "", depth * 4)?;
+
+        match op {
+            Op::Add(count) => writeln!(f, "*p += {};", count)?,
+            Op::Move(count) => writeln!(f, "p += {};", count)?,
+            Op::Clear | Op::ClearUp => writeln!(f, "*p = 0;")?,
+            Op::MulMove(changes) => {
+                for (offset, factor) in changes {
+                    write!(f, "p[{}] += *p * {}; ", offset, factor)?;
+                }
+                writeln!(f, "*p = 0;")?;
+            }
+            Op::Scan(count) => writeln!(f, "while (*p) p += {};", count)?,
+            Op::Output => writeln!(f, "putchar(*p);")?,
+            Op::Input => match config.eof {
+                Eof::Leave => writeln!(f, "if ((c = getchar()) != EOF) *p = c;")?,
+                Eof::Zero => writeln!(f, "*p = (c = getchar()) != EOF ? c : 0;")?,
+                Eof::MinusOne => writeln!(f, "*p = (c = getchar()) != EOF ? c : -1;")?,
+            },
+            Op::JumpIfZero(_) => {
+                writeln!(f, "while (*p) {{")?;
+                depth += 1;
+            }
+            Op::JumpIfNonZero(_) => writeln!(f, "}}")?,
+            Op::Break => {}
+            Op::Define(_) | Op::Return | Op::Call => unreachable!("checked before generating"),
+        }
+    }
+
+    writeln!(f, "    return 0;")?;
+    writeln!(f, "}}")
+}
+
+fn qbe(program: &[Op], config: &MemoryConfig, f: &mut String) -> FmtResult {
+    let (load, store, size) = match config.width {
+        CellWidth::U8 => ("loadub", "storeb", 1),
+        CellWidth::U16 => ("loaduh", "storeh", 2),
+        CellWidth::U32 => ("loaduw", "storew", 4),
+    };
+    let (len, start) = tape(config);
+
+    writeln!(f, "data $tape = {{ z {} }}", len * size)?;
+    writeln!(f)?;
+    writeln!(f, "export function w $main() {{")?;
+    writeln!(f, "@start")?;
+    writeln!(f, "    %p =l add $tape, {}", start * size)?;
+
+    for (index, op) in program.iter().enumerate() {
+        match op {
+            Op::Add(count) => {
+                writeln!(f, "    %v =w {} %p", load)?;
+                writeln!(f, "    %v =w add %v, {}", count)?;
+                writeln!(f, "    {} %v, %p", store)?;
+            }
+            Op::Move(count) => {
+                writeln!(f, "    %p =l add %p, {}", *count * size as isize)?;
+            }
+            Op::Clear | Op::ClearUp => {
+                writeln!(f, "    {} 0, %p", store)?;
+            }
+            Op::MulMove(changes) => {
+                writeln!(f, "    %v =w {} %p", load)?;
+                for (offset, factor) in changes {
+                    writeln!(f, "    %a =l add %p, {}", *offset * size as isize)?;
+                    writeln!(f, "    %t =w {} %a", load)?;
+                    writeln!(f, "    %m =w mul %v, {}", factor)?;
+                    writeln!(f, "    %t =w add %t, %m")?;
+                    writeln!(f, "    {} %t, %a", store)?;
+                }
+                writeln!(f, "    {} 0, %p", store)?;
+            }
+            Op::Scan(count) => {
+                writeln!(f, "@scan_{}", index)?;
+                writeln!(f, "    %v =w {} %p", load)?;
+                writeln!(f, "    jnz %v, @scan_body_{0}, @scan_end_{0}", index)?;
+                writeln!(f, "@scan_body_{}", index)?;
+                writeln!(f, "    %p =l add %p, {}", *count * size as isize)?;
+                writeln!(f, "    jmp @scan_{}", index)?;
+                writeln!(f, "@scan_end_{}", index)?;
+            }
+            Op::Output => {
+                writeln!(f, "    %v =w {} %p", load)?;
+                writeln!(f, "    call $putchar(w %v)")?;
+            }
+            Op::Input => {
+                writeln!(f, "    %c =w call $getchar()")?;
+                writeln!(f, "    %e =w ceqw %c, -1")?;
+                writeln!(f, "    jnz %e, @eof_{0}, @read_{0}", index)?;
+                writeln!(f, "@read_{}", index)?;
+                writeln!(f, "    {} %c, %p", store)?;
+                writeln!(f, "    jmp @input_end_{}", index)?;
+                writeln!(f, "@eof_{}", index)?;
+                match config.eof {
+                    Eof::Leave => {}
+                    Eof::Zero => writeln!(f, "    {} 0, %p", store)?,
+                    Eof::MinusOne => writeln!(f, "    {} -1, %p", store)?,
+                }
+                writeln!(f, "@input_end_{}", index)?;
+            }
+            Op::JumpIfZero(_) => {
+                writeln!(f, "    %v =w {} %p", load)?;
+                writeln!(f, "    jnz %v, @loop_{0}, @loop_end_{0}", index)?;
+                writeln!(f, "@loop_{}", index)?;
+            }
+            Op::JumpIfNonZero(target) => {
+                // Loops are labelled by the index of their opening instruction
+                let open = target - 1;
+                writeln!(f, "    %v =w {} %p", load)?;
+                writeln!(f, "    jnz %v, @loop_{0}, @loop_end_{0}", open)?;
+                writeln!(f, "@loop_end_{}", open)?;
+            }
+            Op::Break => {}
+            Op::Define(_) | Op::Return | Op::Call => unreachable!("checked before generating"),
+        }
+    }
+
+    writeln!(f, "    ret 0")?;
+    writeln!(f, "}}")
+}
@@ -1,3 +1,8 @@
+pub mod generate;
+pub mod optimize;
 pub mod parse;
+pub mod profile;
 pub mod run;
+#[cfg(test)]
+mod test;
 pub mod token;
@@ -1,29 +1,137 @@
-use bf::{parse::parse, run::Memory, token::scan};
+use bf::{
+    generate::{generate, Target},
+    optimize::{optimize, optimize_with_spans},
+    parse::{parse, pretty, Expr},
+    run::{CellWidth, Eof, Execution, Memory, MemoryConfig, Overflow, Status, Tape},
+    token::{scan, Dialect},
+};
 use clap::{AppSettings, Clap, FromArgMatches, IntoApp};
 use std::{
-    convert::TryInto,
-    io::{self, Write},
+    convert::TryFrom,
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
 };
 
 fn main() {
+    if let Err(err) = try_main(Args::parse()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn try_main(args: Args) -> Result<(), Box<dyn Error>> {
+    let mode = args.mode.unwrap_or(Mode::Repl {
+        memory: Default::default(),
+        dialect: Default::default(),
+    });
+
+    match mode {
+        Mode::Run {
+            input,
+            profile,
+            memory,
+            dialect,
+        } => {
+            let (program, spans) = optimize_with_spans(&load(&input, dialect.into())?);
+            let mut execution = if profile {
+                Execution::profiled(program, spans)
+            } else {
+                Execution::new(program)
+            };
+            let mut memory = Memory::new(memory.into());
+
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            let (mut input, mut output) = (stdin.lock(), BufWriter::new(stdout.lock()));
+
+            while execution.resume(&mut memory, &mut input, &mut output)? == Status::Breakpoint {
+                let pointer = *memory.index();
+                eprint!("{}", memory.dump(pointer - 8, pointer + 8));
+            }
+
+            if let Some(profile) = execution.profile() {
+                eprint!("{}", profile.report(execution.program()));
+            }
+        }
+        Mode::Repl { memory, dialect } => repl(
+            memory.into(),
+            Dialect {
+                debug: true,
+                ..dialect.into()
+            },
+        ),
+        Mode::Fmt {
+            input,
+            minify,
+            dialect,
+        } => {
+            let program = load(&input, dialect.into())?;
+
+            if minify {
+                let program: String = program.iter().map(ToString::to_string).collect();
+                println!("{}", program);
+            } else {
+                print!("{}", pretty(&program));
+            }
+        }
+        Mode::Compile {
+            input,
+            target,
+            output,
+            memory,
+            dialect,
+        } => {
+            let program = optimize(&load(&input, dialect.into())?);
+            let output = output.unwrap_or_else(|| input.with_extension(target.extension()));
+            fs::write(output, generate(&program, &memory.into(), target)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read and parse a program, prefixing any errors with the path
+fn load(path: &Path, dialect: Dialect) -> Result<Vec<Expr>, String> {
+    let src = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    parse(scan(src.chars(), dialect)).map_err(|err| format!("{}:{}", path.display(), err))
+}
+
+fn save_memory(memory: &Memory, path: &Path) -> Result<(), Box<dyn Error>> {
+    memory.save(BufWriter::new(File::create(path)?))?;
+    Ok(())
+}
+
+fn load_memory(path: &Path) -> Result<Memory, Box<dyn Error>> {
+    Ok(Memory::load(BufReader::new(File::open(path)?))?)
+}
+
+fn repl(config: MemoryConfig, dialect: Dialect) {
     let mut buffer = String::new();
-    let mut memory = Memory::new(100);
+    let mut memory = Memory::new(config);
+    let mut paused = None;
+    let mut profiling = false;
 
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
 
-        io::stdin().read_line(&mut buffer).unwrap();
+        if io::stdin().read_line(&mut buffer).unwrap() == 0 {
+            break;
+        }
         let input = buffer.trim();
 
-        if input.starts_with('/') {
-            let input = input[1..].split_whitespace();
+        if let Some(input) = input.strip_prefix('/') {
+            let input = input.split_whitespace();
 
             let input = Command::into_app()
                 .setting(AppSettings::DisableHelpFlags)
                 .setting(AppSettings::DisableVersion)
                 .setting(AppSettings::NoAutoHelp)
                 .setting(AppSettings::NoBinaryName)
+                .setting(AppSettings::AllowNegativeNumbers)
                 .try_get_matches_from(input);
 
             buffer.clear();
@@ -36,28 +144,267 @@ fn main() {
                 }
             };
 
-            match input {
-                Command::Get { location } => match location {
-                    Location::Memory { index } => println!("{}", memory.get(index)),
-                    Location::Current => println!("{}", memory.get(*memory.index())),
-                    Location::Pointer => println!("{}", memory.index()),
-                },
-                Command::Set { value, location } => match location {
-                    Location::Memory { index } => *memory.get(index) = value.try_into().unwrap(),
-                    Location::Current => *memory.get(*memory.index()) = value.try_into().unwrap(),
-                    Location::Pointer => *memory.index_mut() = value,
-                },
-                Command::Clear => memory.clear(),
+            let result = match input {
+                Command::Get { location } => get(&mut memory, location),
+                Command::Set { value, location } => set(&mut memory, location, value),
+                Command::Clear => {
+                    memory.clear();
+                    Ok(())
+                }
+                Command::Reset => {
+                    memory = Memory::new(*memory.config());
+                    paused = None;
+                    Ok(())
+                }
+                Command::Save { path } => save_memory(&memory, &path),
+                Command::Load { path } => load_memory(&path).map(|loaded| {
+                    memory = loaded;
+                    paused = None;
+                }),
+                Command::Step { count } => {
+                    resume(&mut memory, &mut paused, Some(count)).map(report_finished)
+                }
+                Command::Continue => resume(&mut memory, &mut paused, None).map(report_finished),
+                Command::Profile => {
+                    profiling = !profiling;
+                    println!("profiling {}", if profiling { "on" } else { "off" });
+                    Ok(())
+                }
+                Command::Tape { from, to } => {
+                    tape(&memory, from, to);
+                    Ok(())
+                }
                 Command::Exit => break,
+            };
+
+            if let Err(err) = result {
+                eprintln!("{}", err);
             }
             continue;
         }
 
-        let input = parse(scan(input.chars()));
+        let input = parse(scan(input.chars(), dialect));
+        buffer.clear();
+
+        match input {
+            Ok(input) => {
+                let (program, spans) = optimize_with_spans(&input);
+                paused = Some(if profiling {
+                    Execution::profiled(program, spans)
+                } else {
+                    Execution::new(program)
+                });
+                if let Err(err) = resume(&mut memory, &mut paused, None) {
+                    eprintln!("{}", err);
+                }
+            }
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+/// Run the paused program for some number of steps, or until it stops by itself,
+/// returning how many steps it took in total if it finished
+fn resume(
+    memory: &mut Memory,
+    paused: &mut Option<Execution>,
+    steps: Option<u64>,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    let mut execution = match paused.take() {
+        Some(execution) => execution,
+        None => {
+            eprintln!("nothing is paused");
+            return Ok(None);
+        }
+    };
 
-        memory.run(&input);
+    let (mut input, mut output) = (io::stdin(), io::stdout());
+    let status = match steps {
+        Some(steps) => {
+            let mut status = Status::Running;
+            for _ in 0..steps {
+                status = execution.step(memory, &mut input, &mut output)?;
+                if status != Status::Running {
+                    break;
+                }
+            }
+            output.flush()?;
+            status
+        }
+        None => execution.resume(memory, &mut input, &mut output)?,
+    };
 
-        buffer.clear();
+    if status == Status::Finished {
+        if let Some(profile) = execution.profile() {
+            eprint!("{}", profile.report(execution.program()));
+        }
+
+        return Ok(Some(execution.steps()));
+    }
+
+    if status == Status::Breakpoint {
+        print!("breakpoint, ");
+    }
+
+    match execution.program().get(execution.index()) {
+        Some(op) => println!(
+            "next is {}: {} after {} steps",
+            execution.index(),
+            op,
+            execution.steps()
+        ),
+        None => println!("at the end after {} steps", execution.steps()),
+    }
+
+    *paused = Some(execution);
+    Ok(None)
+}
+
+fn report_finished(steps: Option<u64>) {
+    if let Some(steps) = steps {
+        println!("finished after {} steps", steps);
+    }
+}
+
+/// Most cells `/tape` shows at once
+const MAX_TAPE_CELLS: isize = 64;
+
+/// Print the cells from `from` to `to`, defaulting to a window around the pointer
+fn tape(memory: &Memory, from: Option<isize>, to: Option<isize>) {
+    let from = from.unwrap_or_else(|| memory.index().saturating_sub(8));
+    let to = to
+        .unwrap_or_else(|| from.saturating_add(16))
+        .min(from.saturating_add(MAX_TAPE_CELLS - 1));
+    print!("{}", memory.dump(from, to));
+}
+
+fn get(memory: &mut Memory, location: Location) -> Result<(), Box<dyn Error>> {
+    match location {
+        Location::Memory { index } => println!("{}", memory.get(index)?),
+        Location::Current => println!("{}", memory.get(*memory.index())?),
+        Location::Pointer => println!("{}", memory.index()),
+    }
+
+    Ok(())
+}
+
+fn set(memory: &mut Memory, location: Location, value: isize) -> Result<(), Box<dyn Error>> {
+    let width = memory.config().width;
+    let cell = || {
+        u32::try_from(value)
+            .ok()
+            .filter(|cell| *cell <= width.max())
+            .ok_or_else(|| format!("{} doesn't fit in {} bit cells", value, width))
+    };
+
+    match location {
+        Location::Memory { index } => memory.set(index, cell()?)?,
+        Location::Current => memory.set(*memory.index(), cell()?)?,
+        Location::Pointer => *memory.index_mut() = value,
+    }
+
+    Ok(())
+}
+
+#[derive(Clap, Debug, Clone)]
+struct Args {
+    #[clap(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Clap, Debug, Clone)]
+enum Mode {
+    /// Run a program from a file, reading from stdin and writing to stdout
+    Run {
+        input: PathBuf,
+        /// Print a report of the hottest loops to stderr once finished
+        #[clap(long)]
+        profile: bool,
+        #[clap(flatten)]
+        memory: MemoryArgs,
+        #[clap(flatten)]
+        dialect: DialectArgs,
+    },
+    /// Run commands interactively, the default when no mode is given
+    ///
+    /// `#` is always a breakpoint here, whether or not `--debug` is given
+    Repl {
+        #[clap(flatten)]
+        memory: MemoryArgs,
+        #[clap(flatten)]
+        dialect: DialectArgs,
+    },
+    /// Print a program with one loop per line, dropping any comments
+    Fmt {
+        input: PathBuf,
+        /// Print everything on a single line instead
+        #[clap(long)]
+        minify: bool,
+        #[clap(flatten)]
+        dialect: DialectArgs,
+    },
+    /// Translate a program into C or QBE source
+    Compile {
+        input: PathBuf,
+        #[clap(long, default_value = "c")]
+        target: Target,
+        /// Defaults to the input path with the target's extension
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        #[clap(flatten)]
+        memory: MemoryArgs,
+        #[clap(flatten)]
+        dialect: DialectArgs,
+    },
+}
+
+#[derive(Clap, Debug, Clone, Default)]
+struct MemoryArgs {
+    /// Bits in each cell: 8, 16 or 32
+    #[clap(long, default_value = "8")]
+    width: CellWidth,
+    /// What happens when a cell goes out of range: wrap or error
+    #[clap(long, default_value = "wrap")]
+    overflow: Overflow,
+    /// Cells the pointer can reach: right, both, or a fixed number of cells
+    #[clap(long, default_value = "right")]
+    tape: Tape,
+    /// What `,` does at the end of input: leave, zero or minus-one
+    #[clap(long, default_value = "leave")]
+    eof: Eof,
+}
+
+#[derive(Clap, Debug, Clone, Default)]
+struct DialectArgs {
+    /// Treat `#` as a breakpoint, printing the tape around the pointer
+    #[clap(long)]
+    debug: bool,
+    /// Enable pbrain procedures, defined with `(` and `)` and called with `:`
+    #[clap(long)]
+    procedures: bool,
+    /// Reject anything that isn't a command or whitespace
+    #[clap(long)]
+    strict: bool,
+}
+
+impl From<DialectArgs> for Dialect {
+    fn from(args: DialectArgs) -> Self {
+        Self {
+            debug: args.debug,
+            procedures: args.procedures,
+            strict: args.strict,
+        }
+    }
+}
+
+impl From<MemoryArgs> for MemoryConfig {
+    fn from(args: MemoryArgs) -> Self {
+        Self {
+            width: args.width,
+            overflow: args.overflow,
+            tape: args.tape,
+            eof: args.eof,
+        }
     }
 }
 
@@ -68,17 +415,42 @@ enum Command {
         location: Location,
     },
     Set {
-        value: usize,
+        value: isize,
         #[clap(subcommand)]
         location: Location,
     },
+    /// Zero every cell, leaving the pointer where it is
     Clear,
+    /// Zero every cell, move the pointer back to the start and forget any paused program
+    Reset,
+    /// Write the tape, pointer and memory config to a file
+    Save {
+        path: PathBuf,
+    },
+    /// Replace the memory with one written by `/save`, forgetting any paused program
+    Load {
+        path: PathBuf,
+    },
+    /// Run the paused program for a number of instructions
+    Step {
+        #[clap(default_value = "1")]
+        count: u64,
+    },
+    /// Run the paused program until the next breakpoint
+    Continue,
+    /// Toggle printing a report of the hottest loops after each program finishes
+    Profile,
+    /// Show the cells between two indices, or around the pointer
+    Tape {
+        from: Option<isize>,
+        to: Option<isize>,
+    },
     Exit,
 }
 
 #[derive(Clap, Debug, Clone)]
 enum Location {
-    Memory { index: usize },
+    Memory { index: isize },
     Current,
     Pointer,
 }
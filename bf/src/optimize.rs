@@ -0,0 +1,223 @@
+use crate::{parse::Expr, token::Span};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Lowers a parsed program into a flat list of `Op`s, folding runs of the same
+/// instruction and replacing common loop idioms with single instructions
+///
+/// Nothing is folded that could behave differently when overflow is an error or the
+/// tape is bounded, so `+-` stays as two `Add`s since the `-` could overflow on its own.
+pub fn optimize(input: &[Expr]) -> Vec<Op> {
+    optimize_with_spans(input).0
+}
+
+/// Same as `optimize`, but also returns the span of the loop each `Op` came from,
+/// which is `None` for anything that isn't a jump or a replaced loop
+pub fn optimize_with_spans(input: &[Expr]) -> (Vec<Op>, Vec<Option<Span>>) {
+    let mut output = Lowered::default();
+    lower(input, &mut output);
+    (output.ops, output.spans)
+}
+
+#[derive(Debug, Clone, Default)]
+struct Lowered {
+    ops: Vec<Op>,
+    spans: Vec<Option<Span>>,
+}
+
+impl Lowered {
+    fn push(&mut self, op: Op, span: Option<Span>) {
+        self.ops.push(op);
+        self.spans.push(span);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// Add to the current cell, wrapping to the cell width
+    Add(i32),
+    /// Move the pointer by the given amount
+    Move(isize),
+    /// `[-]`, set the current cell to zero
+    Clear,
+    /// `[+]`, set the current cell to zero by counting up, which overflows for
+    /// any non-zero cell unless overflow wraps
+    ClearUp,
+    /// `[->+>++<<]`, add the current cell multiplied by each factor to the cell
+    /// at each offset, then set the current cell to zero
+    MulMove(Vec<(isize, i32)>),
+    /// `[>]`, move by the given amount until the current cell is zero
+    Scan(isize),
+    Output,
+    Input,
+    /// Jump to the given index if the current cell is zero
+    JumpIfZero(usize),
+    /// Jump to the given index if the current cell is not zero
+    JumpIfNonZero(usize),
+    /// `#`, pause when running in the debugger
+    Break,
+    /// `(`, define a procedure numbered by the current cell that starts at the next
+    /// instruction, then jump to the given index just past its end
+    Define(usize),
+    /// `)`, jump back to just after the `Call` that started this procedure
+    Return,
+    /// `:`, call the procedure numbered by the current cell
+    Call,
+}
+
+fn lower(input: &[Expr], output: &mut Lowered) {
+    let mut iter = input.iter().peekable();
+
+    while let Some(expr) = iter.next() {
+        match expr {
+            Expr::Inc | Expr::Dec => {
+                let mut count = delta(expr);
+                while iter.next_if_eq(&expr).is_some() {
+                    count += delta(expr);
+                }
+
+                output.push(Op::Add(count), None);
+            }
+
+            Expr::Right | Expr::Left => {
+                let mut count = offset(expr);
+                while iter.next_if_eq(&expr).is_some() {
+                    count += offset(expr);
+                }
+
+                output.push(Op::Move(count), None);
+            }
+
+            Expr::Output => output.push(Op::Output, None),
+            Expr::Input => output.push(Op::Input, None),
+            Expr::Break => output.push(Op::Break, None),
+
+            Expr::Loop(span, body) => {
+                if let Some(op) = idiom(body) {
+                    output.push(op, Some(*span));
+                    continue;
+                }
+
+                let open = output.ops.len();
+                output.push(Op::JumpIfZero(0), Some(*span));
+                lower(body, output);
+                output.push(Op::JumpIfNonZero(open + 1), Some(*span));
+                output.ops[open] = Op::JumpIfZero(output.ops.len());
+            }
+
+            Expr::Procedure(_, body) => {
+                let define = output.ops.len();
+                output.push(Op::Define(0), None);
+                lower(body, output);
+                output.push(Op::Return, None);
+                output.ops[define] = Op::Define(output.ops.len());
+            }
+
+            Expr::Call => output.push(Op::Call, None),
+        }
+    }
+}
+
+/// Recognizes loops that can be replaced with a single `Op`
+fn idiom(body: &[Expr]) -> Option<Op> {
+    match body {
+        [Expr::Dec] => return Some(Op::Clear),
+        [Expr::Inc] => return Some(Op::ClearUp),
+        _ => {}
+    }
+
+    if !body.is_empty() && body.iter().all(|expr| *expr == Expr::Right) {
+        return Some(Op::Scan(body.len() as isize));
+    }
+
+    if !body.is_empty() && body.iter().all(|expr| *expr == Expr::Left) {
+        return Some(Op::Scan(-(body.len() as isize)));
+    }
+
+    // Anything left has to be a balanced loop of only `+-<>` that decrements
+    // the starting cell by exactly one each time around
+    let mut pointer = 0;
+    let (mut lowest, mut highest) = (0, 0);
+    let mut changes: Vec<(isize, i32)> = vec![];
+
+    for expr in body {
+        match expr {
+            Expr::Right | Expr::Left => {
+                pointer += offset(expr);
+                lowest = lowest.min(pointer);
+                highest = highest.max(pointer);
+            }
+            Expr::Inc | Expr::Dec => match changes.iter_mut().find(|(at, _)| *at == pointer) {
+                // Going both ways on a cell could overflow partway through the loop
+                Some((_, count)) if count.signum() != delta(expr) => return None,
+                Some((_, count)) => *count += delta(expr),
+                None => changes.push((pointer, delta(expr))),
+            },
+            _ => return None,
+        }
+    }
+
+    if pointer != 0 {
+        return None;
+    }
+
+    let start = changes.iter().position(|(at, _)| *at == 0)?;
+    if changes.remove(start).1 != -1 {
+        return None;
+    }
+
+    // The loop can only be replaced if it never goes past the cells it changes,
+    // otherwise it could run off a bounded tape that the replacement stays on
+    let offsets = changes.iter().map(|(at, _)| *at).chain([0]);
+    if offsets.clone().min() != Some(lowest) || offsets.max() != Some(highest) {
+        return None;
+    }
+
+    if changes.is_empty() {
+        return Some(Op::Clear);
+    }
+
+    Some(Op::MulMove(changes))
+}
+
+fn delta(expr: &Expr) -> i32 {
+    match expr {
+        Expr::Inc => 1,
+        Expr::Dec => -1,
+        _ => 0,
+    }
+}
+
+fn offset(expr: &Expr) -> isize {
+    match expr {
+        Expr::Right => 1,
+        Expr::Left => -1,
+        _ => 0,
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Add(count) => write!(f, "add {}", count),
+            Self::Move(count) => write!(f, "move {}", count),
+            Self::Clear => write!(f, "clear"),
+            Self::ClearUp => write!(f, "clear_up"),
+            Self::MulMove(changes) => {
+                write!(f, "mul_move")?;
+                for (offset, factor) in changes {
+                    write!(f, " {}*{}", offset, factor)?;
+                }
+                Ok(())
+            }
+            Self::Scan(count) => write!(f, "scan {}", count),
+            Self::Output => write!(f, "output"),
+            Self::Input => write!(f, "input"),
+            Self::JumpIfZero(index) => write!(f, "jump_if_zero {}", index),
+            Self::JumpIfNonZero(index) => write!(f, "jump_if_non_zero {}", index),
+            Self::Break => write!(f, "break"),
+            Self::Define(index) => write!(f, "define {}", index),
+            Self::Return => write!(f, "return"),
+            Self::Call => write!(f, "call"),
+        }
+    }
+}
@@ -1,10 +1,14 @@
-use crate::token::Token;
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use crate::token::{Position, Span, Token};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
 
-pub fn parse(input: impl Iterator<Item = Token>) -> Vec<Expr> {
-    let mut output = vec![vec![]];
+pub fn parse(input: impl Iterator<Item = (Position, Token)>) -> Result<Vec<Expr>, ParseError> {
+    let mut output = vec![];
+    let mut stack: Vec<(Position, Token, Vec<Expr>)> = vec![];
 
-    for token in input {
+    for (position, token) in input {
         let token = match token {
             Token::Right => Expr::Right,
             Token::Left => Expr::Left,
@@ -12,26 +16,106 @@ pub fn parse(input: impl Iterator<Item = Token>) -> Vec<Expr> {
             Token::Dec => Expr::Dec,
             Token::Output => Expr::Output,
             Token::Input => Expr::Input,
-            Token::Open => {
-                output.push(vec![]);
+            Token::Break => Expr::Break,
+            Token::Call => Expr::Call,
+            Token::Open | Token::ProcedureOpen => {
+                stack.push((position, token, output));
+                output = vec![];
+                continue;
+            }
+            Token::Close | Token::ProcedureClose => {
+                let (start, parent) = match stack.pop() {
+                    Some((start, Token::Open, parent)) if token == Token::Close => (start, parent),
+                    Some((start, Token::ProcedureOpen, parent))
+                        if token == Token::ProcedureClose =>
+                    {
+                        (start, parent)
+                    }
+                    _ => return Err(ParseError::UnmatchedClose(position, token)),
+                };
+
+                let span = Span {
+                    start,
+                    end: position,
+                };
+                let body = std::mem::replace(&mut output, parent);
+
+                match token {
+                    Token::Close => Expr::Loop(span, body),
+                    _ => Expr::Procedure(span, body),
+                }
+            }
+            Token::Unknown(char) => return Err(ParseError::UnknownChar(position, char)),
+        };
+
+        output.push(token);
+    }
+
+    match stack.pop() {
+        Some((position, token, _)) => Err(ParseError::UnmatchedOpen(position, token)),
+        None => Ok(output),
+    }
+}
+
+/// Print a program with each loop and procedure on its own lines, indented by how deeply it's nested
+pub fn pretty(input: &[Expr]) -> String {
+    let mut output = String::new();
+    pretty_into(input, 0, &mut output);
+    output
+}
+
+fn pretty_into(input: &[Expr], depth: usize, output: &mut String) {
+    let indent = "    ".repeat(depth);
+    let mut line = String::new();
+
+    for expr in input {
+        let (open, close, body) = match expr {
+            Expr::Loop(_, body) => ('[', ']', body),
+            Expr::Procedure(_, body) => ('(', ')', body),
+            _ => {
+                line.push_str(&expr.to_string());
                 continue;
             }
-            Token::Close => Expr::Loop(output.pop().expect("Stack to not be empty")),
         };
 
-        output
-            .last_mut()
-            .expect("Stack to not be empty")
-            .push(token);
+        if !line.is_empty() {
+            output.push_str(&format!("{}{}\n", indent, line));
+            line.clear();
+        }
+
+        output.push_str(&format!("{}{}\n", indent, open));
+        pretty_into(body, depth + 1, output);
+        output.push_str(&format!("{}{}\n", indent, close));
+    }
+
+    if !line.is_empty() {
+        output.push_str(&format!("{}{}\n", indent, line));
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParseError {
+    /// A `[` or `(` with no matching `]` or `)`
+    UnmatchedOpen(Position, Token),
+    /// A `]` or `)` with no matching `[` or `(`
+    UnmatchedClose(Position, Token),
+    /// A character that isn't a command, in strict mode
+    UnknownChar(Position, char),
+}
 
-    if output.len() == 1 {
-        output.remove(0)
-    } else {
-        panic!("Stack to have exactly one left")
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::UnmatchedOpen(position, token) | Self::UnmatchedClose(position, token) => {
+                write!(f, "{}: unmatched '{}'", position, token)
+            }
+            Self::UnknownChar(position, char) => write!(f, "{}: unexpected {:?}", position, char),
+        }
     }
 }
 
+impl Error for ParseError {}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Expr {
     Right,
@@ -40,7 +124,10 @@ pub enum Expr {
     Dec,
     Output,
     Input,
-    Loop(Vec<Expr>),
+    Break,
+    Loop(Span, Vec<Expr>),
+    Procedure(Span, Vec<Expr>),
+    Call,
 }
 
 impl Display for Expr {
@@ -52,13 +139,22 @@ impl Display for Expr {
             Self::Dec => write!(f, "-"),
             Self::Output => write!(f, "."),
             Self::Input => write!(f, ","),
-            Self::Loop(body) => {
+            Self::Break => write!(f, "#"),
+            Self::Loop(_, body) => {
                 write!(f, "[")?;
                 for item in body {
                     write!(f, "{}", item)?;
                 }
                 write!(f, "]")
             }
+            Self::Procedure(_, body) => {
+                write!(f, "(")?;
+                for item in body {
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+            Self::Call => write!(f, ":"),
         }
     }
 }
@@ -0,0 +1,130 @@
+use crate::{optimize::Op, token::Span};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// How many times each instruction of a program was run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    spans: Vec<Option<Span>>,
+    counts: Vec<u64>,
+}
+
+/// Totals for a single loop in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoopProfile {
+    pub span: Span,
+    /// Index of the first instruction of the loop
+    pub index: usize,
+    /// Number of times the loop was reached
+    pub entered: u64,
+    /// Number of times the loop body finished, or `None` for loops replaced by a single instruction
+    pub iterations: Option<u64>,
+    /// Number of instructions run inside the loop, including the loop itself and any nested loops
+    pub steps: u64,
+}
+
+impl Profile {
+    /// Takes the spans returned by `optimize_with_spans`
+    pub fn new(spans: Vec<Option<Span>>) -> Self {
+        Self {
+            counts: vec![0; spans.len()],
+            spans,
+        }
+    }
+
+    pub(crate) fn record(&mut self, index: usize) {
+        self.counts[index] += 1;
+    }
+
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Every loop that was reached, with the most steps first
+    pub fn loops(&self, program: &[Op]) -> Vec<LoopProfile> {
+        let mut loops = vec![];
+
+        for (index, (op, span)) in program.iter().zip(&self.spans).enumerate() {
+            let span = match span {
+                Some(span) if self.counts[index] != 0 => *span,
+                _ => continue,
+            };
+
+            let (iterations, end) = match op {
+                Op::JumpIfZero(target) => (Some(self.counts[target - 1]), *target),
+                Op::JumpIfNonZero(_) => continue,
+                _ => (None, index + 1),
+            };
+
+            loops.push(LoopProfile {
+                span,
+                index,
+                entered: self.counts[index],
+                iterations,
+                steps: self.counts[index..end].iter().sum(),
+            });
+        }
+
+        loops.sort_by(|a, b| b.steps.cmp(&a.steps).then(a.span.cmp(&b.span)));
+        loops
+    }
+
+    /// Index and count of every instruction that was run, most run first
+    pub fn instructions(&self) -> Vec<(usize, u64)> {
+        let mut instructions: Vec<_> = self
+            .counts
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count != 0)
+            .collect();
+
+        instructions.sort_by(|(a_index, a), (b_index, b)| b.cmp(a).then(a_index.cmp(b_index)));
+        instructions
+    }
+
+    /// A printable summary of the hottest loops and instructions
+    pub fn report<'a>(&'a self, program: &'a [Op]) -> Report<'a> {
+        Report {
+            profile: self,
+            program,
+        }
+    }
+}
+
+pub struct Report<'a> {
+    profile: &'a Profile,
+    program: &'a [Op],
+}
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        writeln!(
+            f,
+            "{:>12} {:>12} {:>12}  loop",
+            "steps", "entered", "iterations"
+        )?;
+        for profile in self.profile.loops(self.program) {
+            let iterations = profile
+                .iterations
+                .map_or_else(|| "-".to_owned(), |iterations| iterations.to_string());
+
+            writeln!(
+                f,
+                "{:>12} {:>12} {:>12}  {} {}",
+                profile.steps,
+                profile.entered,
+                iterations,
+                profile.span,
+                self.program[profile.index]
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>12} {:>8}  instruction", "count", "index")?;
+        for (index, count) in self.profile.instructions() {
+            writeln!(f, "{:>12} {:>8}  {}", count, index, self.program[index])?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,77 +1,704 @@
-use crate::parse::Expr;
-use std::io::{self, Read};
+use crate::{optimize::Op, profile::Profile, token::Span};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{self, BufRead, ErrorKind, Read, Write},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MemoryConfig {
+    pub width: CellWidth,
+    pub overflow: Overflow,
+    pub tape: Tape,
+    pub eof: Eof,
+}
+
+/// Number of bits in each cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    /// Largest value a cell can hold
+    pub fn max(self) -> u32 {
+        match self {
+            Self::U8 => u8::MAX as u32,
+            Self::U16 => u16::MAX as u32,
+            Self::U32 => u32::MAX,
+        }
+    }
+}
+
+/// What happens when a cell goes above its max or below zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Overflow {
+    #[default]
+    Wrap,
+    Error,
+}
+
+/// Which cells the pointer is allowed to reach
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Tape {
+    /// Cells `0..len`, allocated up front
+    Fixed(usize),
+    /// Cells `0..`, growing to the right as needed
+    #[default]
+    Right,
+    /// Any cell, growing in both directions as needed
+    Both,
+}
+
+/// What `,` stores in the current cell once the input has run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Eof {
+    #[default]
+    Leave,
+    Zero,
+    MinusOne,
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "8" => Self::U8,
+            "16" => Self::U16,
+            "32" => Self::U32,
+            _ => return Err(format!("unknown cell width {}, expected 8, 16 or 32", s)),
+        })
+    }
+}
+
+impl Display for CellWidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::U8 => write!(f, "8"),
+            Self::U16 => write!(f, "16"),
+            Self::U32 => write!(f, "32"),
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "wrap" => Self::Wrap,
+            "error" => Self::Error,
+            _ => return Err(format!("unknown overflow {}, expected wrap or error", s)),
+        })
+    }
+}
+
+impl Display for Overflow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Wrap => write!(f, "wrap"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for Tape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "right" => Self::Right,
+            "both" => Self::Both,
+            len => Self::Fixed(
+                len.parse()
+                    .map_err(|_| format!("unknown tape {}, expected right, both or a length", s))?,
+            ),
+        })
+    }
+}
+
+impl Display for Tape {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Fixed(len) => write!(f, "{}", len),
+            Self::Right => write!(f, "right"),
+            Self::Both => write!(f, "both"),
+        }
+    }
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "leave" => Self::Leave,
+            "zero" => Self::Zero,
+            "minus-one" => Self::MinusOne,
+            _ => {
+                return Err(format!(
+                    "unknown eof {}, expected leave, zero or minus-one",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl Display for Eof {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Leave => write!(f, "leave"),
+            Self::Zero => write!(f, "zero"),
+            Self::MinusOne => write!(f, "minus-one"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    /// The pointer moved to a cell outside of the tape
+    PointerOutOfBounds(isize),
+    /// A cell went out of range while overflow is set to error
+    Overflow(isize),
+    /// `:` called a procedure that hasn't been defined yet
+    UndefinedProcedure(u32),
+    /// `:` nested more than `MAX_CALL_DEPTH` procedure calls
+    CallStackOverflow,
+    /// `)` was run without a `:` to return to
+    UnexpectedReturn,
+    Io(io::Error),
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::PointerOutOfBounds(index) => {
+                write!(f, "pointer moved out of bounds to {}", index)
+            }
+            Self::Overflow(index) => write!(f, "cell {} overflowed", index),
+            Self::UndefinedProcedure(procedure) => {
+                write!(f, "procedure {} has not been defined", procedure)
+            }
+            Self::CallStackOverflow => {
+                write!(f, "procedure calls nested deeper than {}", MAX_CALL_DEPTH)
+            }
+            Self::UnexpectedReturn => write!(f, "returned from outside of a procedure"),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+impl From<io::Error> for RunError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// The first line wasn't `SAVE_HEADER`
+    Header,
+    /// A line that couldn't be read, numbered from 1
+    Line(usize),
+    /// A required key that never appeared
+    Missing(&'static str),
+    /// The number of cells doesn't fit the tape
+    Cells,
+    Io(io::Error),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Header => write!(f, "not a saved memory, expected {:?}", SAVE_HEADER),
+            Self::Line(line) => write!(f, "line {} is invalid", line),
+            Self::Missing(key) => write!(f, "missing {}", key),
+            Self::Cells => write!(f, "cells don't fit the tape"),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// First line of every saved memory, bumped whenever the format changes
+const SAVE_HEADER: &str = "bf memory 1";
 
 #[derive(Debug, Clone)]
 pub struct Memory {
-    cells: Vec<u8>,
-    index: usize,
+    cells: VecDeque<u32>,
+    /// Position of cell `0` within `cells`, which is only ever non-zero for `Tape::Both`
+    origin: usize,
+    index: isize,
+    config: MemoryConfig,
 }
 
 impl Memory {
-    pub fn new(start_size: usize) -> Self {
-        Self {
-            cells: Vec::with_capacity(start_size),
+    pub fn new(config: MemoryConfig) -> Self {
+        let mut memory = Self {
+            cells: VecDeque::new(),
+            origin: 0,
             index: 0,
-        }
+            config,
+        };
+        memory.clear();
+        memory
     }
 
-    pub fn index(&self) -> &usize {
+    pub fn config(&self) -> &MemoryConfig {
+        &self.config
+    }
+
+    pub fn index(&self) -> &isize {
         &self.index
     }
 
-    pub fn index_mut(&mut self) -> &mut usize {
+    pub fn index_mut(&mut self) -> &mut isize {
         &mut self.index
     }
 
-    pub fn get(&mut self, index: usize) -> &mut u8 {
-        if index >= self.cells.len() {
-            self.cells.resize(index + 1, 0);
+    pub fn get(&mut self, index: isize) -> Result<&mut u32, RunError> {
+        let out_of_bounds = RunError::PointerOutOfBounds(index);
+        let mut at = index + self.origin as isize;
+
+        match self.config.tape {
+            Tape::Fixed(_) => {}
+
+            Tape::Right => {
+                if index >= 0 && at as usize >= self.cells.len() {
+                    self.cells.resize(at as usize + 1, 0);
+                }
+            }
+
+            Tape::Both => {
+                if at < 0 {
+                    for _ in at..0 {
+                        self.cells.push_front(0);
+                    }
+                    self.origin += at.unsigned_abs();
+                    at = 0;
+                }
+
+                if at as usize >= self.cells.len() {
+                    self.cells.resize(at as usize + 1, 0);
+                }
+            }
+        }
+
+        if at < 0 {
+            return Err(out_of_bounds);
+        }
+
+        self.cells.get_mut(at as usize).ok_or(out_of_bounds)
+    }
+
+    /// Set a cell, failing if the value doesn't fit in the cell width
+    pub fn set(&mut self, index: isize, value: u32) -> Result<(), RunError> {
+        if value > self.config.width.max() {
+            return Err(RunError::Overflow(index));
+        }
+
+        *self.get(index)? = value;
+        Ok(())
+    }
+
+    /// Run a program to completion, reading `,` from `input` and writing `.` to `output`
+    ///
+    /// Breakpoints are ignored, use `Execution` to stop at them.
+    pub fn run(
+        &mut self,
+        program: &[Op],
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), RunError> {
+        let mut execution = Execution::new(program.to_vec());
+        while execution.resume(self, &mut input, &mut output)? != Status::Finished {}
+        Ok(())
+    }
+
+    /// Same as `run`, but counting how many times each instruction ran
+    pub fn profile(
+        &mut self,
+        program: &[Op],
+        spans: Vec<Option<Span>>,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<Profile, RunError> {
+        let mut execution = Execution::profiled(program.to_vec(), spans);
+        while execution.resume(self, &mut input, &mut output)? != Status::Finished {}
+        Ok(execution
+            .profile
+            .expect("profiled execution to have a profile"))
+    }
+
+    /// Value of a cell without growing the tape, or `None` if the pointer can never reach it
+    pub fn peek(&self, index: isize) -> Option<u32> {
+        let cell = index
+            .checked_add(self.origin as isize)
+            .and_then(|at| usize::try_from(at).ok())
+            .and_then(|at| self.cells.get(at));
+
+        if let Some(cell) = cell {
+            return Some(*cell);
+        }
+
+        match self.config.tape {
+            Tape::Fixed(_) => None,
+            Tape::Right => (index >= 0).then_some(0),
+            Tape::Both => Some(0),
+        }
+    }
+
+    /// Two lines showing the index and value of each cell from `from` to `to`,
+    /// with the cell under the pointer in brackets
+    pub fn dump(&self, from: isize, to: isize) -> String {
+        let mut indices = String::new();
+        let mut values = String::new();
+
+        for index in from..=to {
+            let value = self
+                .peek(index)
+                .map_or_else(|| "-".to_owned(), |value| value.to_string());
+            let value = if index == self.index {
+                format!("[{}]", value)
+            } else {
+                value
+            };
+
+            let width = value.len().max(index.to_string().len()) + 1;
+            indices.push_str(&format!("{:>1$}", index, width));
+            values.push_str(&format!("{:>1$}", value, width));
+        }
+
+        format!("{}\n{}\n", indices, values)
+    }
+
+    /// Add `value * factor` to a cell, respecting the configured width and overflow
+    fn add(&mut self, index: isize, value: u32, factor: i32) -> Result<(), RunError> {
+        let MemoryConfig {
+            width, overflow, ..
+        } = self.config;
+        let cur = self.get(index)?;
+
+        *cur = match overflow {
+            Overflow::Wrap => {
+                let change = (value as u64).wrapping_mul(factor as i64 as u64);
+                ((*cur as u64).wrapping_add(change) & width.max() as u64) as u32
+            }
+
+            Overflow::Error => (value as i64)
+                .checked_mul(factor as i64)
+                .and_then(|change| (*cur as i64).checked_add(change))
+                .filter(|result| (0..=width.max() as i64).contains(result))
+                .ok_or(RunError::Overflow(index))? as u32,
+        };
+
+        Ok(())
+    }
+
+    /// Zero every cell, leaving the pointer where it is
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.origin = 0;
+
+        if let Tape::Fixed(len) = self.config.tape {
+            self.cells.resize(len, 0);
+        }
+    }
+
+    /// Write the config, pointer and every cell as lines of text, which `load` reads back
+    pub fn save(&self, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "{}", SAVE_HEADER)?;
+        writeln!(output, "width {}", self.config.width)?;
+        writeln!(output, "overflow {}", self.config.overflow)?;
+        writeln!(output, "tape {}", self.config.tape)?;
+        writeln!(output, "eof {}", self.config.eof)?;
+        writeln!(output, "pointer {}", self.index)?;
+        writeln!(output, "origin {}", self.origin)?;
+
+        write!(output, "cells")?;
+        for cell in &self.cells {
+            write!(output, " {}", cell)?;
+        }
+        writeln!(output)?;
+
+        output.flush()
+    }
+
+    pub fn load(input: impl BufRead) -> Result<Self, LoadError> {
+        let mut lines = input.lines();
+        if lines.next().transpose()?.as_deref() != Some(SAVE_HEADER) {
+            return Err(LoadError::Header);
+        }
+
+        let mut config = MemoryConfig::default();
+        let mut index = None;
+        let mut origin = None;
+        let mut cells = None;
+
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            // The header is line 1, so the first line here is line 2
+            let number = number + 2;
+            let invalid = || LoadError::Line(number);
+
+            let (key, value) = line.split_once(' ').unwrap_or((&line, ""));
+            match key {
+                "width" => config.width = value.parse().map_err(|_| invalid())?,
+                "overflow" => config.overflow = value.parse().map_err(|_| invalid())?,
+                "tape" => config.tape = value.parse().map_err(|_| invalid())?,
+                "eof" => config.eof = value.parse().map_err(|_| invalid())?,
+                "pointer" => index = Some(value.parse().map_err(|_| invalid())?),
+                "origin" => origin = Some(value.parse().map_err(|_| invalid())?),
+                "cells" => {
+                    let parsed: Result<_, _> = value.split_whitespace().map(str::parse).collect();
+                    cells = Some(parsed.map_err(|_| invalid())?);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let memory = Self {
+            cells: cells.ok_or(LoadError::Missing("cells"))?,
+            origin: origin.ok_or(LoadError::Missing("origin"))?,
+            index: index.ok_or(LoadError::Missing("pointer"))?,
+            config,
+        };
+
+        let fits = match config.tape {
+            Tape::Fixed(len) => memory.origin == 0 && memory.cells.len() == len,
+            Tape::Right => memory.origin == 0,
+            Tape::Both => memory.origin <= memory.cells.len(),
+        };
+        let in_range = memory.cells.iter().all(|cell| *cell <= config.width.max());
+
+        if fits && in_range {
+            Ok(memory)
+        } else {
+            Err(LoadError::Cells)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// There are more instructions to run
+    Running,
+    /// Stopped just after a `#`
+    Breakpoint,
+    /// Every instruction has been run
+    Finished,
+}
+
+/// Most procedure calls that can be nested at once, so runaway recursion errors
+/// instead of growing the call stack forever
+pub const MAX_CALL_DEPTH: usize = 1 << 16;
+
+/// A program that can be run on a `Memory` one instruction at a time, and paused partway through
+#[derive(Debug, Clone)]
+pub struct Execution {
+    program: Vec<Op>,
+    index: usize,
+    steps: u64,
+    profile: Option<Profile>,
+    /// Start index of each defined procedure
+    procedures: HashMap<u32, usize>,
+    /// Index to return to for each procedure being called
+    calls: Vec<usize>,
+}
+
+impl Execution {
+    pub fn new(program: Vec<Op>) -> Self {
+        Self {
+            program,
+            index: 0,
+            steps: 0,
+            profile: None,
+            procedures: HashMap::new(),
+            calls: vec![],
         }
+    }
+
+    /// Same as `new`, but keeping a `Profile` of every instruction run,
+    /// taking the spans returned by `optimize_with_spans`
+    pub fn profiled(program: Vec<Op>, spans: Vec<Option<Span>>) -> Self {
+        Self {
+            profile: Some(Profile::new(spans)),
+            ..Self::new(program)
+        }
+    }
+
+    pub fn program(&self) -> &[Op] {
+        &self.program
+    }
+
+    /// Index of the next instruction to run
+    pub fn index(&self) -> usize {
+        self.index
+    }
 
-        self.cells.get_mut(index).expect("cell to exist")
+    /// Number of instructions run so far
+    pub fn steps(&self) -> u64 {
+        self.steps
     }
 
-    pub fn run(&mut self, input: &[Expr]) {
-        for expr in input {
-            match expr {
-                Expr::Right => {
-                    self.index = self.index.checked_add(1).unwrap();
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Run until hitting a breakpoint or the end of the program
+    pub fn resume(
+        &mut self,
+        memory: &mut Memory,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Status, RunError> {
+        loop {
+            match self.step(memory, input, output)? {
+                Status::Running => {}
+                status => {
+                    output.flush()?;
+                    return Ok(status);
                 }
+            }
+        }
+    }
+
+    /// Run a single instruction
+    pub fn step(
+        &mut self,
+        memory: &mut Memory,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Status, RunError> {
+        let op = match self.program.get(self.index) {
+            Some(op) => op,
+            None => return Ok(Status::Finished),
+        };
 
-                Expr::Left => {
-                    self.index = self.index.checked_sub(1).unwrap();
+        if let Some(profile) = &mut self.profile {
+            profile.record(self.index);
+        }
+
+        self.index += 1;
+        self.steps += 1;
+
+        match op {
+            Op::Add(count) => {
+                memory.add(memory.index, 1, *count)?;
+            }
+
+            Op::Move(count) => {
+                memory.get(memory.index + count)?;
+                memory.index += count;
+            }
+
+            Op::Clear => {
+                *memory.get(memory.index)? = 0;
+            }
+
+            Op::ClearUp => {
+                let overflow = memory.config.overflow;
+                let cell = memory.get(memory.index)?;
+                if *cell != 0 && overflow == Overflow::Error {
+                    return Err(RunError::Overflow(memory.index));
+                }
+                *cell = 0;
+            }
+
+            Op::MulMove(changes) => {
+                let value = *memory.get(memory.index)?;
+                if value != 0 {
+                    for (offset, factor) in changes {
+                        memory.add(memory.index + offset, value, *factor)?;
+                    }
+                    *memory.get(memory.index)? = 0;
                 }
+            }
 
-                Expr::Inc => {
-                    let cur = self.get(self.index);
-                    *cur = cur.wrapping_add(1);
+            Op::Scan(count) => {
+                while *memory.get(memory.index)? != 0 {
+                    memory.get(memory.index + count)?;
+                    memory.index += count;
                 }
+            }
+
+            Op::Output => {
+                output.write_all(&[*memory.get(memory.index)? as u8])?;
+            }
+
+            Op::Input => {
+                let mut buffer = [0];
+                let value = match input.read_exact(&mut buffer) {
+                    Ok(()) => Some(buffer[0] as u32),
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => match memory.config.eof {
+                        Eof::Leave => None,
+                        Eof::Zero => Some(0),
+                        Eof::MinusOne => Some(memory.config.width.max()),
+                    },
+                    Err(err) => return Err(err.into()),
+                };
 
-                Expr::Dec => {
-                    let cur = self.get(self.index);
-                    *cur = cur.wrapping_sub(1);
+                if let Some(value) = value {
+                    *memory.get(memory.index)? = value;
                 }
+            }
 
-                Expr::Output => {
-                    print!("{}", *self.get(self.index) as char);
+            Op::JumpIfZero(target) => {
+                if *memory.get(memory.index)? == 0 {
+                    self.index = *target;
                 }
+            }
 
-                Expr::Input => {
-                    let mut buffer = [0];
-                    io::stdin().read_exact(&mut buffer).unwrap();
-                    *self.get(self.index) = buffer[0];
+            Op::JumpIfNonZero(target) => {
+                if *memory.get(memory.index)? != 0 {
+                    self.index = *target;
                 }
+            }
 
-                Expr::Loop(body) => {
-                    while *self.get(self.index) != 0 {
-                        self.run(body)
-                    }
+            Op::Break => return Ok(Status::Breakpoint),
+
+            Op::Define(end) => {
+                let procedure = *memory.get(memory.index)?;
+                self.procedures.insert(procedure, self.index);
+                self.index = *end;
+            }
+
+            Op::Return => {
+                self.index = self.calls.pop().ok_or(RunError::UnexpectedReturn)?;
+            }
+
+            Op::Call => {
+                let procedure = *memory.get(memory.index)?;
+                let start = *self
+                    .procedures
+                    .get(&procedure)
+                    .ok_or(RunError::UndefinedProcedure(procedure))?;
+
+                if self.calls.len() >= MAX_CALL_DEPTH {
+                    return Err(RunError::CallStackOverflow);
                 }
+                self.calls.push(self.index);
+                self.index = start;
             }
         }
-    }
 
-    pub fn clear(&mut self) {
-        self.cells.clear()
+        Ok(Status::Running)
     }
 }
@@ -0,0 +1,480 @@
+use crate::{
+    generate::{generate, GenerateError, Target},
+    optimize::{optimize, optimize_with_spans, Op},
+    parse::{parse, pretty, ParseError},
+    run::{
+        CellWidth, Eof, Execution, LoadError, Memory, MemoryConfig, Overflow, RunError, Status,
+        Tape,
+    },
+    token::{scan, Dialect, Position, Span, Token},
+};
+
+/// Parse, optimize and run a program on a fresh `Memory`, returning everything it output
+fn run(src: &str, config: MemoryConfig, input: &[u8]) -> Result<Vec<u8>, RunError> {
+    run_dialect(src, Dialect::default(), config, input)
+}
+
+fn run_dialect(
+    src: &str,
+    dialect: Dialect,
+    config: MemoryConfig,
+    input: &[u8],
+) -> Result<Vec<u8>, RunError> {
+    let program = optimize(&parse(scan(src.chars(), dialect)).unwrap());
+
+    let mut output = vec![];
+    Memory::new(config).run(&program, input, &mut output)?;
+    Ok(output)
+}
+
+#[test]
+fn hello_world() {
+    const SRC: &str = include_str!("../dev/hello_world.b");
+
+    let output = run(SRC, MemoryConfig::default(), b"").unwrap();
+    assert_eq!(output, b"Hello World!\n");
+}
+
+#[test]
+fn cat() {
+    const SRC: &str = include_str!("../dev/cat.b");
+
+    let config = MemoryConfig {
+        eof: Eof::Zero,
+        ..Default::default()
+    };
+
+    let output = run(SRC, config, b"meow\nmeow\n").unwrap();
+    assert_eq!(output, b"meow\nmeow\n");
+}
+
+#[test]
+fn rot13() {
+    const SRC: &str = include_str!("../dev/rot13.b");
+
+    let output = run(SRC, MemoryConfig::default(), b"Hello, World!").unwrap();
+    assert_eq!(output, b"Uryyb, Jbeyq!");
+}
+
+#[test]
+fn fold_runs() {
+    let program = optimize(&parse(scan("+++--><<<".chars(), Dialect::default())).unwrap());
+    assert_eq!(
+        program,
+        vec![Op::Add(3), Op::Add(-2), Op::Move(1), Op::Move(-3)]
+    );
+}
+
+#[test]
+fn idioms() {
+    let program =
+        optimize(&parse(scan("[-][+][>>][<][->+>---<<]".chars(), Dialect::default())).unwrap());
+    assert_eq!(
+        program,
+        vec![
+            Op::Clear,
+            Op::ClearUp,
+            Op::Scan(2),
+            Op::Scan(-1),
+            Op::MulMove(vec![(1, 1), (2, -3)]),
+        ]
+    );
+
+    // These could overflow or leave a bounded tape partway through, so they stay loops
+    for src in ["[->+-<]", "[+--]", "[-<>]"] {
+        let program = optimize(&parse(scan(src.chars(), Dialect::default())).unwrap());
+        assert!(matches!(program[0], Op::JumpIfZero(_)), "{}", src);
+    }
+}
+
+#[test]
+fn unmatched_brackets() {
+    assert_eq!(
+        parse(scan("+[\n[]".chars(), Dialect::default())),
+        Err(ParseError::UnmatchedOpen(
+            Position { line: 1, column: 2 },
+            Token::Open
+        ))
+    );
+    assert_eq!(
+        parse(scan("+\n ]".chars(), Dialect::default())),
+        Err(ParseError::UnmatchedClose(
+            Position { line: 2, column: 2 },
+            Token::Close
+        ))
+    );
+}
+
+#[test]
+fn eof() {
+    let eof = |eof| {
+        let config = MemoryConfig {
+            eof,
+            ..Default::default()
+        };
+        run("+,.", config, b"").unwrap()
+    };
+
+    assert_eq!(eof(Eof::Leave), [1]);
+    assert_eq!(eof(Eof::Zero), [0]);
+    assert_eq!(eof(Eof::MinusOne), [255]);
+}
+
+#[test]
+fn overflow() {
+    let config = MemoryConfig {
+        overflow: Overflow::Error,
+        ..Default::default()
+    };
+
+    assert!(matches!(run("-", config, b""), Err(RunError::Overflow(0))));
+    assert_eq!(run("-.", MemoryConfig::default(), b"").unwrap(), [255]);
+
+    // `[+]` only clears a cell by wrapping, so it has to overflow just like the loop would
+    assert!(matches!(
+        run("+[+]", config, b""),
+        Err(RunError::Overflow(0))
+    ));
+    assert_eq!(run("[+]+.", config, b"").unwrap(), [1]);
+    assert_eq!(run("+[+].", MemoryConfig::default(), b"").unwrap(), [0]);
+
+    // Runs that add up to nothing still overflow partway through
+    assert!(matches!(
+        run("-+.", config, b""),
+        Err(RunError::Overflow(0))
+    ));
+    assert_eq!(run("-+.", MemoryConfig::default(), b"").unwrap(), [0]);
+}
+
+#[test]
+fn wide_cells() {
+    let config = MemoryConfig {
+        width: CellWidth::U16,
+        overflow: Overflow::Error,
+        ..Default::default()
+    };
+
+    // Outputs 1 only if 16 * 16 didn't wrap around to 0
+    const SRC: &str = "++++++++++++++++[>++++++++++++++++<-]>[[-]<+>]<.";
+
+    assert_eq!(run(SRC, config, b"").unwrap(), [1]);
+    assert_eq!(run(SRC, MemoryConfig::default(), b"").unwrap(), [0]);
+}
+
+#[test]
+fn tape_bounds() {
+    assert!(matches!(
+        run("<", MemoryConfig::default(), b""),
+        Err(RunError::PointerOutOfBounds(-1))
+    ));
+
+    // Moves that add up to nothing still go out of bounds partway through
+    assert!(matches!(
+        run("<>.", MemoryConfig::default(), b""),
+        Err(RunError::PointerOutOfBounds(-1))
+    ));
+    assert!(matches!(
+        run("+[-<>]", MemoryConfig::default(), b""),
+        Err(RunError::PointerOutOfBounds(-1))
+    ));
+
+    let config = MemoryConfig {
+        tape: Tape::Fixed(2),
+        ..Default::default()
+    };
+    assert!(matches!(
+        run(">>", config, b""),
+        Err(RunError::PointerOutOfBounds(2))
+    ));
+
+    let config = MemoryConfig {
+        tape: Tape::Both,
+        ..Default::default()
+    };
+    assert_eq!(run("<<+>>-<<.", config, b"").unwrap(), [1]);
+}
+
+/// Uses a loop, `MulMove`, `Scan` and input, to cover everything the targets generate
+const GENERATE_SRC: &str = ",[->++<]>[>]<[.-]";
+
+fn generate_eofs(target: Target) -> Vec<String> {
+    let program = optimize(&parse(scan(GENERATE_SRC.chars(), Dialect::default())).unwrap());
+
+    [Eof::Leave, Eof::Zero, Eof::MinusOne]
+        .iter()
+        .map(|&eof| {
+            let config = MemoryConfig {
+                eof,
+                ..Default::default()
+            };
+            generate(&program, &config, target).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn generate_c() {
+    const LEAVE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+
+static uint8_t tape[65536];
+
+int main(void) {
+    uint8_t *p = tape + 0;
+    int c;
+    if ((c = getchar()) != EOF) *p = c;
+    p[1] += *p * 2; *p = 0;
+    p += 1;
+    while (*p) p += 1;
+    p += -1;
+    while (*p) {
+        putchar(*p);
+        *p += -1;
+    }
+    return 0;
+}
+"#;
+
+    let generated = generate_eofs(Target::C);
+    assert_eq!(generated[0], LEAVE);
+
+    let input = "    if ((c = getchar()) != EOF) *p = c;\n";
+    assert_eq!(
+        generated[1],
+        LEAVE.replace(input, "    *p = (c = getchar()) != EOF ? c : 0;\n")
+    );
+    assert_eq!(
+        generated[2],
+        LEAVE.replace(input, "    *p = (c = getchar()) != EOF ? c : -1;\n")
+    );
+}
+
+#[test]
+fn generate_qbe() {
+    const LEAVE: &str = r#"data $tape = { z 65536 }
+
+export function w $main() {
+@start
+    %p =l add $tape, 0
+    %c =w call $getchar()
+    %e =w ceqw %c, -1
+    jnz %e, @eof_0, @read_0
+@read_0
+    storeb %c, %p
+    jmp @input_end_0
+@eof_0
+@input_end_0
+    %v =w loadub %p
+    %a =l add %p, 1
+    %t =w loadub %a
+    %m =w mul %v, 2
+    %t =w add %t, %m
+    storeb %t, %a
+    storeb 0, %p
+    %p =l add %p, 1
+@scan_3
+    %v =w loadub %p
+    jnz %v, @scan_body_3, @scan_end_3
+@scan_body_3
+    %p =l add %p, 1
+    jmp @scan_3
+@scan_end_3
+    %p =l add %p, -1
+    %v =w loadub %p
+    jnz %v, @loop_5, @loop_end_5
+@loop_5
+    %v =w loadub %p
+    call $putchar(w %v)
+    %v =w loadub %p
+    %v =w add %v, -1
+    storeb %v, %p
+    %v =w loadub %p
+    jnz %v, @loop_5, @loop_end_5
+@loop_end_5
+    ret 0
+}
+"#;
+
+    let generated = generate_eofs(Target::Qbe);
+    assert_eq!(generated[0], LEAVE);
+
+    let eof = "@eof_0\n";
+    assert_eq!(
+        generated[1],
+        LEAVE.replace(eof, "@eof_0\n    storeb 0, %p\n")
+    );
+    assert_eq!(
+        generated[2],
+        LEAVE.replace(eof, "@eof_0\n    storeb -1, %p\n")
+    );
+}
+
+#[test]
+fn generate_procedures() {
+    let dialect = Dialect {
+        procedures: true,
+        ..Default::default()
+    };
+    let program = optimize(&parse(scan("+(-):".chars(), dialect)).unwrap());
+
+    for &target in &[Target::C, Target::Qbe] {
+        assert_eq!(
+            generate(&program, &MemoryConfig::default(), target),
+            Err(GenerateError::Unsupported(Op::Define(4), target))
+        );
+    }
+}
+
+#[test]
+fn breakpoints() {
+    let dialect = Dialect {
+        debug: true,
+        ..Default::default()
+    };
+    let program = optimize(&parse(scan("+#>++#.".chars(), dialect)).unwrap());
+    let mut execution = Execution::new(program);
+    let mut memory = Memory::new(MemoryConfig::default());
+    let mut output = vec![];
+
+    let mut resume = |memory: &mut Memory| {
+        execution
+            .resume(memory, &mut &b""[..], &mut output)
+            .unwrap()
+    };
+
+    assert_eq!(resume(&mut memory), Status::Breakpoint);
+    assert_eq!(*memory.index(), 0);
+    assert_eq!(resume(&mut memory), Status::Breakpoint);
+    assert_eq!(memory.peek(1), Some(2));
+    assert_eq!(resume(&mut memory), Status::Finished);
+    assert_eq!(execution.steps(), 6);
+    assert_eq!(output, [2]);
+}
+
+#[test]
+fn profile() {
+    let (program, spans) =
+        optimize_with_spans(&parse(scan("+++[>++[-]<-]".chars(), Dialect::default())).unwrap());
+    let profile = Memory::new(MemoryConfig::default())
+        .profile(&program, spans, &b""[..], vec![])
+        .unwrap();
+
+    let loops = profile.loops(&program);
+    let span = |start, end| Span {
+        start: Position {
+            line: 1,
+            column: start,
+        },
+        end: Position {
+            line: 1,
+            column: end,
+        },
+    };
+
+    assert_eq!(loops.len(), 2);
+    assert_eq!(loops[0].span, span(4, 13));
+    assert_eq!(loops[0].entered, 1);
+    assert_eq!(loops[0].iterations, Some(3));
+    assert_eq!(loops[0].steps, 19);
+    assert_eq!(loops[1].span, span(8, 10));
+    assert_eq!(loops[1].entered, 3);
+    assert_eq!(loops[1].iterations, None);
+}
+
+#[test]
+fn pretty_print() {
+    let program = parse(scan("a+[->[-]<]b.".chars(), Dialect::default())).unwrap();
+    assert_eq!(
+        pretty(&program),
+        "+\n[\n    ->\n    [\n        -\n    ]\n    <\n]\n.\n"
+    );
+}
+
+#[test]
+fn procedures() {
+    let dialect = Dialect {
+        procedures: true,
+        ..Default::default()
+    };
+
+    // Procedure 0 doubles the next cell, procedure 1 calls it twice
+    const SRC: &str = "(>[->++<]>[-<+>]<<)+(-::+)>+++<:>.";
+    let output = run_dialect(SRC, dialect, MemoryConfig::default(), b"");
+    assert_eq!(output.unwrap(), [12]);
+
+    // Without the dialect they're just comments
+    assert_eq!(run("+(.):.", MemoryConfig::default(), b"").unwrap(), [1, 1]);
+
+    assert!(matches!(
+        run_dialect("+:", dialect, MemoryConfig::default(), b""),
+        Err(RunError::UndefinedProcedure(1))
+    ));
+
+    // Procedure 1 calls itself forever
+    assert!(matches!(
+        run_dialect("+(:):", dialect, MemoryConfig::default(), b""),
+        Err(RunError::CallStackOverflow)
+    ));
+
+    let mut memory = Memory::new(MemoryConfig::default());
+    assert!(matches!(
+        memory.run(&[Op::Return], &b""[..], vec![]),
+        Err(RunError::UnexpectedReturn)
+    ));
+}
+
+#[test]
+fn strict() {
+    let dialect = Dialect {
+        strict: true,
+        ..Default::default()
+    };
+
+    assert!(parse(scan("+ [-]\n\t.".chars(), dialect)).is_ok());
+    assert_eq!(
+        parse(scan("+\n a".chars(), dialect)),
+        Err(ParseError::UnknownChar(
+            Position { line: 2, column: 2 },
+            'a'
+        ))
+    );
+    assert_eq!(
+        parse(scan("#".chars(), dialect)),
+        Err(ParseError::UnknownChar(
+            Position { line: 1, column: 1 },
+            '#'
+        ))
+    );
+}
+
+#[test]
+fn save_load() {
+    let config = MemoryConfig {
+        width: CellWidth::U16,
+        tape: Tape::Both,
+        eof: Eof::MinusOne,
+        ..Default::default()
+    };
+
+    let mut memory = Memory::new(config);
+    let program = optimize(&parse(scan("<<+++>>>++".chars(), Dialect::default())).unwrap());
+    memory.run(&program, &b""[..], vec![]).unwrap();
+
+    let mut saved = vec![];
+    memory.save(&mut saved).unwrap();
+    let mut loaded = Memory::load(&saved[..]).unwrap();
+
+    assert_eq!(loaded.config(), &config);
+    assert_eq!(*loaded.index(), 1);
+    assert_eq!(loaded.dump(-3, 2), memory.dump(-3, 2));
+    assert_eq!(*loaded.get(-2).unwrap(), 3);
+
+    assert!(matches!(
+        Memory::load(&b"width 8"[..]),
+        Err(LoadError::Header)
+    ));
+    assert!(matches!(
+        Memory::load(&b"bf memory 1\nwidth 7\n"[..]),
+        Err(LoadError::Line(2))
+    ));
+}
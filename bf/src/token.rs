@@ -3,8 +3,55 @@ use std::{
     fmt::{Display, Formatter, Result as FmtResult},
 };
 
-pub fn scan(input: impl Iterator<Item = char>) -> impl Iterator<Item = Token> {
-    input.filter_map(|char| char.try_into().ok())
+pub fn scan(
+    input: impl Iterator<Item = char>,
+    dialect: Dialect,
+) -> impl Iterator<Item = (Position, Token)> {
+    let mut position = Position { line: 1, column: 0 };
+
+    input.filter_map(move |char| {
+        if char == '\n' {
+            position.line += 1;
+            position.column = 0;
+            return None;
+        }
+
+        position.column += 1;
+
+        match char.try_into() {
+            Ok(token) if dialect.allows(token) => Some((position, token)),
+            _ if dialect.strict && !char.is_whitespace() => Some((position, Token::Unknown(char))),
+            _ => None,
+        }
+    })
+}
+
+/// Which extensions to classic brainfuck are enabled, every one is off by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Dialect {
+    /// `#` pauses the debugger, or prints the tape when not debugging
+    pub debug: bool,
+    /// pbrain's `(` and `)` to define a procedure numbered by the current cell, and `:` to call one
+    pub procedures: bool,
+    /// Anything other than a command or whitespace is an error, rather than a comment
+    pub strict: bool,
+}
+
+impl Dialect {
+    fn allows(self, token: Token) -> bool {
+        match token {
+            Token::Break => self.debug,
+            Token::ProcedureOpen | Token::ProcedureClose | Token::Call => self.procedures,
+            _ => true,
+        }
+    }
+}
+
+/// Line and column of a character in the source, both starting at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -15,8 +62,21 @@ pub enum Token {
     Dec,
     Output,
     Input,
+    Break,
     Open,
     Close,
+    ProcedureOpen,
+    ProcedureClose,
+    Call,
+    /// Anything that isn't a command, only produced in strict mode
+    Unknown(char),
+}
+
+/// Range of source from the start of one token to the end of another, inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
 }
 
 impl TryFrom<char> for Token {
@@ -30,8 +90,12 @@ impl TryFrom<char> for Token {
             '-' => Self::Dec,
             '.' => Self::Output,
             ',' => Self::Input,
+            '#' => Self::Break,
             '[' => Self::Open,
             ']' => Self::Close,
+            '(' => Self::ProcedureOpen,
+            ')' => Self::ProcedureClose,
+            ':' => Self::Call,
             _ => return Err(()),
         })
     }
@@ -46,8 +110,25 @@ impl Display for Token {
             Self::Dec => write!(f, "-"),
             Self::Output => write!(f, "."),
             Self::Input => write!(f, ","),
+            Self::Break => write!(f, "#"),
             Self::Open => write!(f, "["),
             Self::Close => write!(f, "]"),
+            Self::ProcedureOpen => write!(f, "("),
+            Self::ProcedureClose => write!(f, ")"),
+            Self::Call => write!(f, ":"),
+            Self::Unknown(char) => write!(f, "{}", char),
         }
     }
 }
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
@@ -0,0 +1,123 @@
+//! Local variable access with contiguous slot frames, against the `HashMap` per frame design they replaced
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use sonance::{parser, vm::Frames, vm::VM};
+
+/// The previous design, kept here as a baseline
+#[derive(Default)]
+struct HashMapFrames {
+    frames: Vec<HashMapFrame>,
+}
+
+#[derive(Default)]
+struct HashMapFrame {
+    variables: HashMap<u16, u8>,
+    return_index: u32,
+}
+
+impl HashMapFrames {
+    fn call(&mut self, return_index: u32, _stack_base: u32, args: &[u8]) {
+        self.frames.push(HashMapFrame {
+            variables: (0..).zip(args.iter().copied()).collect(),
+            return_index,
+        });
+    }
+
+    fn ret(&mut self) -> Option<u32> {
+        Some(self.frames.pop()?.return_index)
+    }
+
+    fn load(&self, variable: u16) -> Option<u8> {
+        let frame = self.frames.last()?;
+        Some(frame.variables.get(&variable).copied().unwrap_or(0))
+    }
+
+    fn store(&mut self, variable: u16, value: u8) -> Option<()> {
+        self.frames.last_mut()?.variables.insert(variable, value);
+        Some(())
+    }
+}
+
+const SLOTS: u16 = 8;
+const DEPTH: u32 = 16;
+const ACCESSES: u16 = 256;
+
+/// Calls `DEPTH` deep, updating every local `ACCESSES` times in each frame
+macro_rules! workload {
+    ($frames:expr) => {{
+        let frames = &mut $frames;
+        for depth in 0..DEPTH {
+            frames.call(depth, 0, &[1, 2]);
+            for access in 0..ACCESSES {
+                let slot = access % SLOTS;
+                let value = frames.load(slot).unwrap();
+                frames.store(slot, value.wrapping_add(1)).unwrap();
+            }
+        }
+        for _ in 0..DEPTH {
+            black_box(frames.ret());
+        }
+    }};
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frames");
+
+    group.bench_function("hash_map", |b| {
+        b.iter(|| workload!(HashMapFrames::default()))
+    });
+    group.bench_function("slots", |b| b.iter(|| workload!(Frames::default())));
+    group.bench_function("slots_reserved", |b| {
+        b.iter(|| {
+            let mut frames = Frames::default();
+            frames.reserve(SLOTS).unwrap();
+            workload!(frames)
+        })
+    });
+
+    group.finish();
+}
+
+/// Sum `0..count` in a loop that does nothing but local variable access
+fn vm(c: &mut Criterion) {
+    let program = parser::parse(
+        "
+        call #sum 2 200 0
+        halt
+
+        #sum
+        locals &count &total
+        #loop
+        load &total
+        load &count
+        add
+        store &total
+        load &count
+        sub 1
+        dupe
+        store &count
+        jump_if #done
+        jump #loop
+        #done
+        load &total
+        return 1
+        end
+        ",
+    )
+    .unwrap();
+
+    let mut group = c.benchmark_group("vm");
+    group.bench_with_input(BenchmarkId::new("sum", 200), &program, |b, program| {
+        b.iter(|| {
+            let mut vm = VM::new(program.clone());
+            vm.run().unwrap();
+            black_box(vm.stack)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, frames, vm);
+criterion_main!(benches);
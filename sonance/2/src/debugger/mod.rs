@@ -0,0 +1,296 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Result, Write},
+};
+
+use crate::{
+    device::DeviceError,
+    disassembler::{self, Decoded},
+    instruction::Instruction,
+    program::Program,
+    vm::{VMError, VM},
+};
+
+/// Number of instructions shown either side of the current one by `list`
+const LIST_CONTEXT: usize = 5;
+
+/// Most bytes shown at once by `device`
+const MAX_DEVICE_RANGE: u32 = 256;
+
+/// Interactive control over a `VM`, one command at a time
+///
+/// - `break <label|address>`, `delete <label|address>`, `breakpoints`
+/// - `step [count]`: run instructions, stepping into calls
+/// - `next`: run an instruction, stepping over calls
+/// - `finish`: run until the current call returns
+/// - `continue`: run until a breakpoint or the program halts
+/// - `stack`, `frames`, `heap <from> <to>`, `device <from> <to>`: inspect state,
+///   showing `-` for device registers that can't be read without side effects
+/// - `list`: disassembly around the current instruction
+/// - `quit`
+///
+pub struct Debugger<'a> {
+    pub vm: VM<'a>,
+    labels: BTreeMap<String, u32>,
+    decoded: Vec<Decoded>,
+    breakpoints: BTreeSet<u32>,
+    halted: bool,
+}
+
+/// Why running stopped
+#[derive(Debug, Clone)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(u32),
+    Halted,
+    Error(VMError),
+}
+
+impl<'a> Debugger<'a> {
+    /// Takes the program the VM was created from, for its labels and disassembly
+    pub fn new(vm: VM<'a>, program: &Program) -> Self {
+        Self {
+            vm,
+            labels: program.labels.clone(),
+            // Anything after an undecodable byte just won't show up in `list`
+            decoded: disassembler::decode(&program.code).unwrap_or_default(),
+            breakpoints: BTreeSet::new(),
+            halted: false,
+        }
+    }
+
+    /// Run a single command, returning `false` once the user wants to quit
+    pub fn command(&mut self, line: &str, output: &mut impl Write) -> Result<bool> {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let args: Vec<_> = args.collect();
+
+        match (command, args.as_slice()) {
+            ("quit" | "q", []) => return Ok(false),
+
+            ("break" | "b", [location]) => match self.address(location) {
+                Some(address) => {
+                    self.breakpoints.insert(address);
+                    writeln!(output, "breakpoint at {address}")?;
+                }
+                None => writeln!(output, "no label or address {location}")?,
+            },
+            ("delete" | "d", [location]) => match self.address(location) {
+                Some(address) if self.breakpoints.remove(&address) => {
+                    writeln!(output, "deleted breakpoint at {address}")?
+                }
+                _ => writeln!(output, "no breakpoint at {location}")?,
+            },
+            ("breakpoints", []) => {
+                for address in &self.breakpoints {
+                    writeln!(output, "{}", self.describe(*address))?;
+                }
+            }
+
+            ("step" | "s", []) => {
+                let stop = self.step();
+                self.report(stop, output)?;
+            }
+            ("step" | "s", [count]) => match count.parse::<usize>() {
+                Ok(count) => {
+                    let mut stop = Stop::Stepped;
+                    for _ in 0..count {
+                        stop = self.step();
+                        if !matches!(stop, Stop::Stepped) {
+                            break;
+                        }
+                    }
+                    self.report(stop, output)?;
+                }
+                Err(_) => writeln!(output, "invalid count {count}")?,
+            },
+            ("next" | "n", []) => {
+                let stop = self.step_over();
+                self.report(stop, output)?;
+            }
+            ("finish" | "f", []) => {
+                let stop = self.finish();
+                self.report(stop, output)?;
+            }
+            ("continue" | "c", []) => {
+                let stop = self.run_until(|_| false);
+                self.report(stop, output)?;
+            }
+
+            ("stack", []) => writeln!(output, "{:?}", self.vm.stack)?,
+            ("frames", []) => {
+                for (depth, (frame, slots)) in self.vm.frames.iter().enumerate() {
+                    writeln!(
+                        output,
+                        "#{depth} returns to {}: {slots:?}",
+                        frame.return_index
+                    )?;
+                }
+            }
+            ("heap", [from, to]) => match (from.parse::<usize>(), to.parse::<usize>()) {
+                (Ok(from), Ok(to)) => match self.vm.heap.get(from..to) {
+                    Some(values) => writeln!(output, "{values:?}")?,
+                    None => writeln!(output, "heap is only {} bytes", self.vm.heap.len())?,
+                },
+                _ => writeln!(output, "invalid range {from} {to}")?,
+            },
+            ("device", [from, to]) => match (from.parse::<u32>(), to.parse::<u32>()) {
+                (Ok(from), Ok(to)) if to.saturating_sub(from) > MAX_DEVICE_RANGE => {
+                    writeln!(output, "can only show {MAX_DEVICE_RANGE} bytes at once")?
+                }
+                (Ok(from), Ok(to)) => {
+                    let values: std::result::Result<Vec<_>, DeviceError> = (from..to)
+                        .map(|index| self.vm.devices.peek(index))
+                        .collect();
+                    match values {
+                        Ok(values) => {
+                            let values: Vec<_> = values
+                                .into_iter()
+                                .map(|value| {
+                                    value.map_or("-".to_owned(), |value| value.to_string())
+                                })
+                                .collect();
+                            writeln!(output, "[{}]", values.join(", "))?
+                        }
+                        Err(error) => writeln!(output, "{error}")?,
+                    }
+                }
+                _ => writeln!(output, "invalid range {from} {to}")?,
+            },
+            ("list" | "l", []) => self.list(output)?,
+
+            _ => writeln!(output, "unknown command {line}")?,
+        }
+
+        Ok(true)
+    }
+
+    /// A label name or a plain address
+    fn address(&self, location: &str) -> Option<u32> {
+        let label = location.strip_prefix('#').unwrap_or(location);
+        self.labels
+            .get(label)
+            .copied()
+            .or_else(|| location.parse().ok())
+    }
+
+    pub fn step(&mut self) -> Stop {
+        self.run_until(|_| true)
+    }
+
+    /// Step, running any call to completion
+    pub fn step_over(&mut self) -> Stop {
+        let next = self
+            .vm
+            .instructions
+            .get(self.vm.instruction_index as usize)
+            .and_then(|code| Instruction::try_from(*code).ok());
+
+        match next {
+            Some(Instruction::Call) => {
+                let depth = self.vm.frames.depth();
+                self.run_until(|vm| vm.frames.depth() <= depth)
+            }
+            _ => self.step(),
+        }
+    }
+
+    /// Run until the current call returns
+    pub fn finish(&mut self) -> Stop {
+        let depth = self.vm.frames.depth();
+        self.run_until(|vm| vm.frames.depth() < depth)
+    }
+
+    /// Run at least one instruction, then keep going until `done`, a breakpoint, or the program stops
+    pub fn run_until(&mut self, done: impl Fn(&VM) -> bool) -> Stop {
+        if self.halted {
+            return Stop::Halted;
+        }
+
+        loop {
+            match self.vm.step() {
+                Ok(true) => {
+                    self.halted = true;
+                    return Stop::Halted;
+                }
+                Ok(false) => {}
+                Err(error) => {
+                    self.halted = true;
+                    return Stop::Error(error);
+                }
+            }
+
+            if done(&self.vm) {
+                return Stop::Stepped;
+            }
+
+            if self.breakpoints.contains(&self.vm.instruction_index) {
+                return Stop::Breakpoint(self.vm.instruction_index);
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop, output: &mut impl Write) -> Result<()> {
+        match stop {
+            Stop::Stepped => {}
+            Stop::Breakpoint(address) => writeln!(output, "hit breakpoint at {address}")?,
+            Stop::Halted => return writeln!(output, "halted"),
+            Stop::Error(error) => return writeln!(output, "error: {error}"),
+        }
+
+        writeln!(output, "{}", self.describe(self.vm.instruction_index))
+    }
+
+    /// An address along with any labels and the instruction there
+    fn describe(&self, address: u32) -> String {
+        let labels: Vec<_> = self
+            .labels
+            .iter()
+            .filter(|(_, label)| **label == address)
+            .map(|(name, _)| format!("#{name} "))
+            .collect();
+
+        let instruction = self
+            .decoded
+            .iter()
+            .find(|decoded| decoded.address == address)
+            .map_or_else(|| "?".to_owned(), format_decoded);
+
+        format!("{address:>6}  {}{instruction}", labels.concat())
+    }
+
+    fn list(&self, output: &mut impl Write) -> Result<()> {
+        let current = self
+            .decoded
+            .iter()
+            .position(|decoded| decoded.address >= self.vm.instruction_index)
+            .unwrap_or(self.decoded.len());
+
+        let start = current.saturating_sub(LIST_CONTEXT);
+        let end = (current + LIST_CONTEXT + 1).min(self.decoded.len());
+
+        for decoded in &self.decoded[start..end] {
+            let marker = if decoded.address == self.vm.instruction_index {
+                '>'
+            } else if self.breakpoints.contains(&decoded.address) {
+                '*'
+            } else {
+                ' '
+            };
+
+            writeln!(output, "{marker} {}", self.describe(decoded.address))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_decoded(decoded: &Decoded) -> String {
+    match decoded.operand {
+        Some(operand) => format!("{} {operand}", decoded.instruction),
+        None => decoded.instruction.to_string(),
+    }
+}
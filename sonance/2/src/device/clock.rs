@@ -0,0 +1,141 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    device::{kind, register_byte, set_register_byte, status, Device, DeviceError},
+    program::Reader,
+    vm::SnapshotError,
+};
+
+/// A monotonic clock with a one-shot timer
+///
+/// - `1`: Perform command
+///     - Read: Always `device::status::OK`
+///     - Write:
+///         - `0`: Latch the time since the clock started into Elapsed
+///         - `1`: Restart the clock
+///         - `2`: Start the timer
+///
+/// - `2`: Timer Expired, `1` once Timer Duration has passed since the timer started
+/// - `3`: Reserved
+/// - `4..12`: Elapsed microseconds as a `u64`, read only
+/// - `12..16`: Timer Duration in microseconds
+///
+pub struct Clock {
+    start: Instant,
+    timer: Option<Instant>,
+
+    elapsed: u64,
+    duration: u32,
+}
+
+mod register {
+    pub const COMMAND: u32 = 1;
+    pub const TIMER_EXPIRED: u32 = 2;
+    pub const ELAPSED: u32 = 4;
+    pub const DURATION: u32 = 12;
+}
+
+mod command {
+    pub const LATCH: u8 = 0;
+    pub const RESTART: u8 = 1;
+    pub const START_TIMER: u8 = 2;
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            timer: None,
+            elapsed: 0,
+            duration: 0,
+        }
+    }
+
+    fn timer_expired(&self) -> bool {
+        self.timer
+            .is_some_and(|timer| timer.elapsed() >= Duration::from_micros(self.duration.into()))
+    }
+
+    /// Reading never has side effects, so `read` and `peek` both go through here
+    fn register(&self, index: u32) -> Result<u8, DeviceError> {
+        if let Some(byte) = register_byte(index, register::ELAPSED, 8) {
+            return Ok(self.elapsed.to_le_bytes()[byte]);
+        }
+        if let Some(byte) = register_byte(index, register::DURATION, 4) {
+            return Ok(self.duration.to_le_bytes()[byte]);
+        }
+
+        match index {
+            register::COMMAND => Ok(status::OK),
+            register::TIMER_EXPIRED => Ok(self.timer_expired() as u8),
+            index => Err(DeviceError::OutOfBounds(index)),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Clock {
+    fn kind(&self) -> u8 {
+        kind::CLOCK
+    }
+
+    fn read(&mut self, index: u32) -> Result<u8, DeviceError> {
+        self.register(index)
+    }
+
+    fn peek(&self, index: u32) -> Option<u8> {
+        self.register(index).ok()
+    }
+
+    fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError> {
+        if register_byte(index, register::ELAPSED, 8).is_some() {
+            return Err(DeviceError::ReadOnly(index));
+        }
+        if let Some(byte) = register_byte(index, register::DURATION, 4) {
+            set_register_byte(&mut self.duration, byte, value);
+            return Ok(());
+        }
+
+        match (index, value) {
+            (register::COMMAND, command::LATCH) => {
+                self.elapsed = self
+                    .start
+                    .elapsed()
+                    .as_micros()
+                    .try_into()
+                    .unwrap_or(u64::MAX)
+            }
+            (register::COMMAND, command::RESTART) => self.start = Instant::now(),
+            (register::COMMAND, command::START_TIMER) => self.timer = Some(Instant::now()),
+            (register::COMMAND, command) => return Err(DeviceError::InvalidCommand(command)),
+            (register::TIMER_EXPIRED, _) => return Err(DeviceError::ReadOnly(index)),
+            (index, _) => return Err(DeviceError::OutOfBounds(index)),
+        }
+
+        Ok(())
+    }
+
+    /// The latched Elapsed and Timer Duration registers, since host time can't be restored
+    fn snapshot(&self) -> Vec<u8> {
+        let mut snapshot = self.elapsed.to_le_bytes().to_vec();
+        snapshot.extend(self.duration.to_le_bytes());
+        snapshot
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader(snapshot);
+
+        let elapsed = reader.u64()?;
+        let duration = reader.u32()?;
+
+        self.elapsed = elapsed;
+        self.duration = duration;
+
+        Ok(())
+    }
+}
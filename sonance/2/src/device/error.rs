@@ -0,0 +1,22 @@
+use thiserror::Error;
+
+/// A fault raised by a device, which stops the VM
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DeviceError {
+    #[error("no device has ID {0}")]
+    NoSuchDevice(u8),
+    #[error("index {0} is out of bounds")]
+    OutOfBounds(u32),
+    #[error("index {0} is read only")]
+    ReadOnly(u32),
+    #[error("index {0} is write only")]
+    WriteOnly(u32),
+    #[error("{0} is not a valid command")]
+    InvalidCommand(u8),
+    #[error("slice {0}..{1} is out of bounds")]
+    InvalidSlice(u32, u32),
+    #[error("{0}x{1} is not a valid frame size")]
+    InvalidSize(u32, u32),
+    #[error("length {0} is over the maximum of {1}")]
+    TooLong(u32, u32),
+}
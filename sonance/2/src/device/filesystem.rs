@@ -0,0 +1,240 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::device::{kind, register_byte, status, Device, DeviceError};
+
+/// A Device for reading and writing files inside a host directory
+///
+/// Paths are relative to the root, and can't leave it through `..` or absolute paths. Files can't
+/// be symlinks, and directories can only be symlinks to somewhere else inside the root.
+///
+/// - `1`: Perform command on Path, clearing it afterwards
+///     - Read: Status of previous command, from `device::status`
+///     - Write:
+///         - `0`: Open for reading
+///         - `1`: Create for writing, truncating any existing file
+///         - `2`: Open for appending
+///         - `3`: Close the open file
+///         - `4`: Delete
+///         - `5`: Put the file's length in Result
+///
+/// - `2..4`: Reserved
+/// - `4..8`: Result, the length from command `5`, read only
+/// - `8`: Path, each byte written is appended to it, write only
+///     - Paths longer than `MAX_PATH_LENGTH` bytes are invalid, and stop growing
+/// - `9`: Data, reading or writing the next byte of the open file
+///     - Reading past the end gives `0` with `device::status::END_OF_FILE`
+///
+/// Opening a file replaces any that are already open, and the open file isn't included in snapshots.
+pub struct Filesystem {
+    root: PathBuf,
+    path: Vec<u8>,
+    file: Option<Open>,
+
+    status: u8,
+    result: u32,
+}
+
+enum Open {
+    Read(BufReader<File>),
+    Write(BufWriter<File>),
+}
+
+/// Longest path a guest can write, so it can't exhaust the host's memory one byte at a time
+pub const MAX_PATH_LENGTH: usize = 4096;
+
+mod register {
+    pub const COMMAND: u32 = 1;
+    pub const RESULT: u32 = 4;
+    pub const PATH: u32 = 8;
+    pub const DATA: u32 = 9;
+}
+
+mod command {
+    pub const OPEN: u8 = 0;
+    pub const CREATE: u8 = 1;
+    pub const APPEND: u8 = 2;
+    pub const CLOSE: u8 = 3;
+    pub const DELETE: u8 = 4;
+    pub const LENGTH: u8 = 5;
+}
+
+impl Filesystem {
+    /// Sandbox to `root`, which must exist
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            path: vec![],
+            file: None,
+            status: status::OK,
+            result: 0,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The host path for `path`, or `None` if it isn't inside the root
+    fn resolve(&self, path: &[u8]) -> Option<PathBuf> {
+        if path.len() > MAX_PATH_LENGTH {
+            return None;
+        }
+
+        let path = Path::new(std::str::from_utf8(path).ok()?);
+        if path.as_os_str().is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        // Opening a symlink follows it even if it dangles, which could create a file outside
+        let resolved = self.root.join(path);
+        if resolved
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+        {
+            return None;
+        }
+
+        // Directories could still be symlinks, so check where they really end up
+        let parent = resolved.parent()?.canonicalize().ok()?;
+        parent.starts_with(&self.root).then(|| {
+            parent.join(
+                resolved
+                    .file_name()
+                    .expect("path should end in a file name"),
+            )
+        })
+    }
+
+    fn run_command(&mut self, command: u8) -> Result<(), DeviceError> {
+        let path = std::mem::take(&mut self.path);
+
+        let result = match command {
+            command::CLOSE => match self.file.take() {
+                Some(Open::Write(mut file)) => file.flush(),
+                Some(Open::Read(_)) => Ok(()),
+                None => {
+                    self.status = status::NO_STREAM;
+                    return Ok(());
+                }
+            },
+
+            command::OPEN
+            | command::CREATE
+            | command::APPEND
+            | command::DELETE
+            | command::LENGTH => {
+                let Some(path) = self.resolve(&path) else {
+                    self.status = status::INVALID_PATH;
+                    return Ok(());
+                };
+
+                match command {
+                    command::OPEN => File::open(path)
+                        .map(|file| self.file = Some(Open::Read(BufReader::new(file)))),
+                    command::CREATE | command::APPEND => OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(command == command::CREATE)
+                        .append(command == command::APPEND)
+                        .open(path)
+                        .map(|file| self.file = Some(Open::Write(BufWriter::new(file)))),
+                    command::DELETE => std::fs::remove_file(path),
+                    command::LENGTH => std::fs::metadata(path).map(|metadata| {
+                        self.result = metadata.len().try_into().unwrap_or(u32::MAX)
+                    }),
+                    _ => unreachable!("only path commands get here"),
+                }
+            }
+
+            command => return Err(DeviceError::InvalidCommand(command)),
+        };
+
+        self.status = match result {
+            Ok(()) => status::OK,
+            Err(_) => status::IO_ERROR,
+        };
+
+        Ok(())
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let Some(Open::Read(file)) = &mut self.file else {
+            self.status = status::NO_STREAM;
+            return 0;
+        };
+
+        let mut byte = [0];
+        let (status, byte) = match file.read_exact(&mut byte) {
+            Ok(()) => (status::OK, byte[0]),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => (status::END_OF_FILE, 0),
+            Err(_) => (status::IO_ERROR, 0),
+        };
+        self.status = status;
+        byte
+    }
+
+    fn write_data(&mut self, value: u8) {
+        let Some(Open::Write(file)) = &mut self.file else {
+            self.status = status::NO_STREAM;
+            return;
+        };
+
+        self.status = match file.write_all(&[value]) {
+            Ok(()) => status::OK,
+            Err(_) => status::IO_ERROR,
+        };
+    }
+}
+
+impl Device for Filesystem {
+    fn kind(&self) -> u8 {
+        kind::FILESYSTEM
+    }
+
+    fn read(&mut self, index: u32) -> Result<u8, DeviceError> {
+        if let Some(byte) = register_byte(index, register::RESULT, 4) {
+            return Ok(self.result.to_le_bytes()[byte]);
+        }
+
+        match index {
+            register::COMMAND => Ok(self.status),
+            register::PATH => Err(DeviceError::WriteOnly(index)),
+            register::DATA => Ok(self.read_data()),
+            index => Err(DeviceError::OutOfBounds(index)),
+        }
+    }
+
+    /// Everything but Data, since reading it takes a byte from the open file
+    fn peek(&self, index: u32) -> Option<u8> {
+        if let Some(byte) = register_byte(index, register::RESULT, 4) {
+            return Some(self.result.to_le_bytes()[byte]);
+        }
+
+        (index == register::COMMAND).then_some(self.status)
+    }
+
+    fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError> {
+        if register_byte(index, register::RESULT, 4).is_some() {
+            return Err(DeviceError::ReadOnly(index));
+        }
+
+        match index {
+            register::COMMAND => self.run_command(value)?,
+            // One byte past the max is kept, so the path is still known to be too long
+            register::PATH if self.path.len() > MAX_PATH_LENGTH => {}
+            register::PATH => self.path.push(value),
+            register::DATA => self.write_data(value),
+            index => return Err(DeviceError::OutOfBounds(index)),
+        }
+
+        Ok(())
+    }
+}
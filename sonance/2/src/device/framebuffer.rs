@@ -0,0 +1,372 @@
+use std::io::Write;
+
+use crate::{
+    device::{kind, register_byte, set_register_byte, status, Device, DeviceError},
+    program::Reader,
+    vm::SnapshotError,
+};
+
+/// A headless framebuffer of RGB pixels, which presents frames as PPM or PNG images
+///
+/// - `1`: Perform command
+///     - Read: Status of previous command, from `device::status`
+///     - Write:
+///         - `0`: Present, writing the frame to the output
+///         - `1`: Clear every pixel to Color
+///         - `2`: Resize to Width by Height, clearing every pixel to Color
+///
+/// - `2..4`: Reserved
+/// - `4..8`: Width, applied by the resize command
+/// - `8..12`: Height, applied by the resize command
+/// - `12..15`: Color, as red, green and blue
+/// - `15..32`: Reserved
+/// - `32..`: Pixels, three bytes each, row by row from the top left
+///
+/// Resizing to more than `MAX_SIZE` on either side fails with `DeviceError::InvalidSize`, as does
+/// presenting an empty frame as a PNG. The output and format aren't included in snapshots.
+#[derive(Default)]
+pub struct Framebuffer<'a> {
+    pub pixels: Vec<u8>,
+    output: Option<Box<dyn Write + 'a>>,
+    format: Format,
+
+    width: u32,
+    height: u32,
+
+    status: u8,
+    next_width: u32,
+    next_height: u32,
+    color: [u8; 3],
+}
+
+/// How presented frames are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Binary PPM, which can be read back as a stream of frames by tools like ffmpeg
+    #[default]
+    Ppm,
+    /// Uncompressed PNG
+    Png,
+}
+
+/// Largest width or height a frame can have, so a guest can't exhaust the host's memory
+pub const MAX_SIZE: u32 = 4096;
+
+mod register {
+    pub const COMMAND: u32 = 1;
+    pub const WIDTH: u32 = 4;
+    pub const HEIGHT: u32 = 8;
+    pub const COLOR: u32 = 12;
+    pub const PIXELS: u32 = 32;
+}
+
+mod command {
+    pub const PRESENT: u8 = 0;
+    pub const CLEAR: u8 = 1;
+    pub const RESIZE: u8 = 2;
+}
+
+impl<'a> Framebuffer<'a> {
+    /// A black frame with no output
+    ///
+    /// # Panics
+    ///
+    /// If either side is over `MAX_SIZE`
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut framebuffer = Self {
+            next_width: width,
+            next_height: height,
+            ..Default::default()
+        };
+        framebuffer
+            .resize()
+            .expect("frame should be no larger than MAX_SIZE");
+        framebuffer
+    }
+
+    /// Present frames to `output`, one image after another
+    pub fn set_output<T: Write + 'a>(&mut self, output: T) {
+        self.output = Some(Box::new(output));
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The current frame as a binary PPM image
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(&self.pixels);
+        ppm
+    }
+
+    /// The current frame as an uncompressed PNG image, or `None` if it's empty since PNGs can't be
+    pub fn png(&self) -> Option<Vec<u8>> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+
+        let mut header = self.width.to_be_bytes().to_vec();
+        header.extend(self.height.to_be_bytes());
+        // 8 bit RGB, then the only compression and filter methods, and no interlacing
+        header.extend([8, 2, 0, 0, 0]);
+
+        // Every row starts with the filter it uses, which is always none
+        let mut rows = Vec::with_capacity(self.pixels.len() + self.height as usize);
+        for row in self.pixels.chunks_exact(self.width as usize * 3) {
+            rows.push(0);
+            rows.extend(row);
+        }
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&rows));
+        png_chunk(&mut png, b"IEND", &[]);
+        Some(png)
+    }
+
+    fn resize(&mut self) -> Result<(), DeviceError> {
+        if self.next_width > MAX_SIZE || self.next_height > MAX_SIZE {
+            return Err(DeviceError::InvalidSize(self.next_width, self.next_height));
+        }
+
+        self.width = self.next_width;
+        self.height = self.next_height;
+        self.pixels = self
+            .color
+            .repeat(self.width as usize * self.height as usize);
+        Ok(())
+    }
+
+    fn run_command(&mut self, command: u8) -> Result<(), DeviceError> {
+        self.status = match command {
+            command::PRESENT => {
+                let image = match self.format {
+                    Format::Ppm => self.ppm(),
+                    Format::Png => self
+                        .png()
+                        .ok_or(DeviceError::InvalidSize(self.width, self.height))?,
+                };
+                match &mut self.output {
+                    Some(output) => match output.write_all(&image).and_then(|()| output.flush()) {
+                        Ok(()) => status::OK,
+                        Err(_) => status::IO_ERROR,
+                    },
+                    None => status::NO_STREAM,
+                }
+            }
+
+            command::CLEAR => {
+                let color = self.color;
+                self.pixels
+                    .chunks_exact_mut(3)
+                    .for_each(|pixel| pixel.copy_from_slice(&color));
+                status::OK
+            }
+
+            command::RESIZE => {
+                self.resize()?;
+                status::OK
+            }
+
+            command => return Err(DeviceError::InvalidCommand(command)),
+        };
+
+        Ok(())
+    }
+
+    /// Reading never has side effects, so `read` and `peek` both go through here
+    fn register(&self, index: u32) -> Result<u8, DeviceError> {
+        let registers = [
+            (register::WIDTH, self.next_width),
+            (register::HEIGHT, self.next_height),
+        ];
+        for (start, register) in registers {
+            if let Some(byte) = register_byte(index, start, 4) {
+                return Ok(register.to_le_bytes()[byte]);
+            }
+        }
+        if let Some(byte) = register_byte(index, register::COLOR, 3) {
+            return Ok(self.color[byte]);
+        }
+
+        match index {
+            register::COMMAND => Ok(self.status),
+
+            index if index >= register::PIXELS => self
+                .pixels
+                .get((index - register::PIXELS) as usize)
+                .copied()
+                .ok_or(DeviceError::OutOfBounds(index)),
+
+            index => Err(DeviceError::OutOfBounds(index)),
+        }
+    }
+}
+
+/// Append a PNG chunk of `kind` holding `data`, along with its length and checksum
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+/// `data` as a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with the smallest window, since nothing refers back into it
+    let mut zlib = vec![0x08, 0x1d];
+
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        zlib.push(last as u8);
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend(block);
+    }
+
+    zlib.extend(adler32(data).to_be_bytes());
+    zlib
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1, 0);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[byte] = crc;
+        byte += 1;
+    }
+
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+impl<'a> Device for Framebuffer<'a> {
+    fn kind(&self) -> u8 {
+        kind::FRAMEBUFFER
+    }
+
+    fn read(&mut self, index: u32) -> Result<u8, DeviceError> {
+        self.register(index)
+    }
+
+    fn peek(&self, index: u32) -> Option<u8> {
+        self.register(index).ok()
+    }
+
+    fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError> {
+        let registers = [
+            (register::WIDTH, &mut self.next_width),
+            (register::HEIGHT, &mut self.next_height),
+        ];
+        for (start, register) in registers {
+            if let Some(byte) = register_byte(index, start, 4) {
+                set_register_byte(register, byte, value);
+                return Ok(());
+            }
+        }
+        if let Some(byte) = register_byte(index, register::COLOR, 3) {
+            self.color[byte] = value;
+            return Ok(());
+        }
+
+        match index {
+            register::COMMAND => self.run_command(value)?,
+
+            index if index >= register::PIXELS => {
+                *self
+                    .pixels
+                    .get_mut((index - register::PIXELS) as usize)
+                    .ok_or(DeviceError::OutOfBounds(index))? = value
+            }
+
+            index => return Err(DeviceError::OutOfBounds(index)),
+        }
+
+        Ok(())
+    }
+
+    /// Registers followed by the frame size and pixels
+    fn snapshot(&self) -> Vec<u8> {
+        let mut snapshot = vec![self.status];
+        for register in [self.next_width, self.next_height, self.width, self.height] {
+            snapshot.extend(register.to_le_bytes());
+        }
+        snapshot.extend(self.color);
+        snapshot.extend(&self.pixels);
+        snapshot
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader(snapshot);
+
+        let status = reader.u8()?;
+        let next_width = reader.u32()?;
+        let next_height = reader.u32()?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let color = reader.take(3)?.try_into().expect("took 3 bytes");
+        let pixels = reader.0;
+
+        if pixels.len() as u64 != width as u64 * height as u64 * 3 {
+            return Err(SnapshotError::InvalidDeviceState);
+        }
+
+        self.status = status;
+        self.next_width = next_width;
+        self.next_height = next_height;
+        self.width = width;
+        self.height = height;
+        self.color = color;
+        self.pixels = pixels.to_vec();
+
+        Ok(())
+    }
+}
@@ -1,48 +1,77 @@
-use std::io::{Read, Write};
+use std::{
+    io::{ErrorKind, Read, Write},
+    ops::Range,
+};
 
-use crate::device::Device;
+use crate::{
+    device::{kind, register_byte, set_register_byte, status, Device, DeviceError},
+    program::Reader,
+    vm::SnapshotError,
+};
 
 /// A Device for storing data or performing IO
 ///
+/// Multi-byte registers are little endian `u32`s, one byte per index.
+///
 /// - `1`: Perform command on slice
-///     - Read: Result of previous command
+///     - Read: Status of previous command, from `device::status`
 ///     - Write:
 ///         - `0`: Read from input
 ///         - `1`: Read all from input
 ///         - `2`: Write to output
 ///         - `3`: Write all to output
-///         - `4`: Reset
+///         - `4`: Fill with default value
+///         - `5`: Resize memory to Length, filling with default value
 ///
-/// - `2`: Memory Length
-/// - `3`: Slice Start
-/// - `4`: Slice End
-/// - `5`: Default Value
-/// - `6`: IO Index
-/// - `7..9`: Reserved
-/// - `10..`: Memory
+/// - `2..4`: Reserved
+/// - `4..8`: Result, the number of bytes the previous command affected
+/// - `8..12`: Length, applied by the resize command
+/// - `12..16`: Slice Start
+/// - `16..20`: Slice End
+/// - `20`: Default Value
+/// - `21`: IO Index
+/// - `22..32`: Reserved
+/// - `32..`: Memory
 ///
-#[derive(Default)]
+/// Resizing past the max length, `DEFAULT_MAX_LENGTH` unless set with `set_max_length`,
+/// fails with `DeviceError::TooLong`.
 pub struct Memory<'a> {
     pub memory: Vec<u8>,
+    max_length: u32,
 
-    inputs: Option<Vec<Box<dyn Read + 'a>>>,
-    outputs: Option<Vec<Box<dyn Write + 'a>>>,
+    inputs: Vec<Box<dyn Read + 'a>>,
+    outputs: Vec<Box<dyn Write + 'a>>,
 
-    command_result: u8,
-    slice_start: u8,
-    slice_end: u8,
+    status: u8,
+    result: u32,
+    length: u32,
+    slice_start: u32,
+    slice_end: u32,
     default_value: u8,
     io_index: u8,
 }
 
+/// Largest memory a guest can resize to by default, so it can't exhaust the host's memory
+pub const DEFAULT_MAX_LENGTH: u32 = 16 * 1024 * 1024;
+
 mod register {
     pub const COMMAND: u32 = 1;
-    pub const LENGTH: u32 = 2;
-    pub const SLICE_START: u32 = 3;
-    pub const SLICE_END: u32 = 4;
-    pub const DEFAULT_VALUE: u32 = 5;
-    pub const IO_INDEX: u32 = 6;
-    pub const MEMORY: usize = 10;
+    pub const RESULT: u32 = 4;
+    pub const LENGTH: u32 = 8;
+    pub const SLICE_START: u32 = 12;
+    pub const SLICE_END: u32 = 16;
+    pub const DEFAULT_VALUE: u32 = 20;
+    pub const IO_INDEX: u32 = 21;
+    pub const MEMORY: u32 = 32;
+}
+
+mod command {
+    pub const READ: u8 = 0;
+    pub const READ_ALL: u8 = 1;
+    pub const WRITE: u8 = 2;
+    pub const WRITE_ALL: u8 = 3;
+    pub const FILL: u8 = 4;
+    pub const RESIZE: u8 = 5;
 }
 
 impl<'a> Memory<'a> {
@@ -58,86 +87,238 @@ impl<'a> Memory<'a> {
         memory
     }
 
+    pub fn max_length(&self) -> u32 {
+        self.max_length
+    }
+
+    pub fn set_max_length(&mut self, max_length: u32) {
+        self.max_length = max_length;
+    }
+
     pub fn add_input<T: Read + 'a>(&mut self, input: T) {
-        let mut inputs = self.inputs.take().unwrap_or_default();
-        inputs.push(Box::new(input));
-        self.inputs = Some(inputs);
+        self.inputs.push(Box::new(input));
     }
 
     pub fn add_output<T: Write + 'a>(&mut self, output: T) {
-        let mut outputs = self.outputs.take().unwrap_or_default();
-        outputs.push(Box::new(output));
-        self.outputs = Some(outputs);
+        self.outputs.push(Box::new(output));
     }
 
-    pub fn slice(&self) -> &[u8] {
-        &self.memory[self.slice_start as usize..self.slice_end as usize]
+    /// Returns `None` if the slice is out of bounds
+    pub fn slice(&self) -> Option<&[u8]> {
+        self.memory
+            .get(self.slice_start as usize..self.slice_end as usize)
     }
 
-    pub fn slice_mut(&mut self) -> &mut [u8] {
-        &mut self.memory[self.slice_start as usize..self.slice_end as usize]
+    /// Returns `None` if the slice is out of bounds
+    pub fn slice_mut(&mut self) -> Option<&mut [u8]> {
+        self.memory
+            .get_mut(self.slice_start as usize..self.slice_end as usize)
     }
 
-    fn run_command(&mut self, instruction: u8) {
+    fn slice_range(&self) -> Result<Range<usize>, DeviceError> {
+        let range = self.slice_start as usize..self.slice_end as usize;
+        if range.start > range.end || range.end > self.memory.len() {
+            return Err(DeviceError::InvalidSlice(self.slice_start, self.slice_end));
+        }
+
+        Ok(range)
+    }
+
+    fn run_command(&mut self, command: u8) -> Result<(), DeviceError> {
         let io_index = self.io_index as usize;
-        let mut inputs = self.inputs.take().unwrap_or_default();
-        let mut outputs = self.outputs.take().unwrap_or_default();
-
-        self.command_result = (match instruction {
-            0 => inputs[io_index].read(self.slice_mut()).unwrap(),
-            1 => {
-                inputs[io_index].read_exact(self.slice_mut()).unwrap();
-                self.memory.len()
+
+        let result = match command {
+            command::READ | command::READ_ALL => {
+                let range = self.slice_range()?;
+                let slice = &mut self.memory[range];
+
+                match self.inputs.get_mut(io_index) {
+                    Some(input) if command == command::READ => input.read(slice),
+                    Some(input) => input.read_exact(slice).map(|()| slice.len()),
+                    None => {
+                        self.set_status(status::NO_STREAM, 0);
+                        return Ok(());
+                    }
+                }
             }
 
-            2 => outputs[io_index].write(self.slice()).unwrap(),
-            3 => {
-                outputs[io_index].write_all(self.slice()).unwrap();
-                self.memory.len()
+            command::WRITE | command::WRITE_ALL => {
+                let slice = &self.memory[self.slice_range()?];
+
+                match self.outputs.get_mut(io_index) {
+                    Some(output) if command == command::WRITE => output.write(slice),
+                    Some(output) => output
+                        .write_all(slice)
+                        .and_then(|()| output.flush())
+                        .map(|()| slice.len()),
+                    None => {
+                        self.set_status(status::NO_STREAM, 0);
+                        return Ok(());
+                    }
+                }
             }
 
-            4 => {
-                let fill_value = self.default_value;
-                self.slice_mut().fill(fill_value);
-                self.slice_mut().len()
+            command::FILL => {
+                let range = self.slice_range()?;
+                self.memory[range.clone()].fill(self.default_value);
+                Ok(range.len())
             }
 
-            _ => self.command_result as usize,
-        }) as u8;
+            command::RESIZE => {
+                if self.length > self.max_length {
+                    return Err(DeviceError::TooLong(self.length, self.max_length));
+                }
 
-        self.inputs = Some(inputs);
-        self.outputs = Some(outputs);
+                self.memory.resize(self.length as usize, self.default_value);
+                Ok(self.memory.len())
+            }
+
+            command => return Err(DeviceError::InvalidCommand(command)),
+        };
+
+        match result {
+            Ok(len) => self.set_status(status::OK, len as u32),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                self.set_status(status::END_OF_FILE, 0)
+            }
+            Err(_) => self.set_status(status::IO_ERROR, 0),
+        }
+
+        Ok(())
     }
-}
 
-impl<'a> Device for Memory<'a> {
-    fn read(&mut self, index: u32) -> u8 {
+    fn set_status(&mut self, status: u8, result: u32) {
+        self.status = status;
+        self.result = result;
+    }
+
+    /// Reading never has side effects, so `read` and `peek` both go through here
+    fn register(&self, index: u32) -> Result<u8, DeviceError> {
+        let registers = [
+            (register::RESULT, self.result),
+            (register::LENGTH, self.length),
+            (register::SLICE_START, self.slice_start),
+            (register::SLICE_END, self.slice_end),
+        ];
+        for (start, register) in registers {
+            if let Some(byte) = register_byte(index, start, 4) {
+                return Ok(register.to_le_bytes()[byte]);
+            }
+        }
+
         match index {
-            0 => todo!(),
+            register::COMMAND => Ok(self.status),
+            register::DEFAULT_VALUE => Ok(self.default_value),
+            register::IO_INDEX => Ok(self.io_index),
 
-            register::COMMAND => self.command_result,
-            register::LENGTH => self.memory.len() as u8,
-            register::SLICE_START => self.slice_start,
-            register::SLICE_END => self.slice_end,
-            register::DEFAULT_VALUE => self.default_value,
-            register::IO_INDEX => self.io_index,
+            index if index >= register::MEMORY => self
+                .memory
+                .get((index - register::MEMORY) as usize)
+                .copied()
+                .ok_or(DeviceError::OutOfBounds(index)),
 
-            _ => self.memory[index as usize - register::MEMORY],
+            index => Err(DeviceError::OutOfBounds(index)),
         }
     }
+}
 
-    fn write(&mut self, index: u32, value: u8) {
-        match index {
-            0 => todo!(),
+impl Default for Memory<'_> {
+    fn default() -> Self {
+        Self {
+            memory: vec![],
+            max_length: DEFAULT_MAX_LENGTH,
+            inputs: vec![],
+            outputs: vec![],
+            status: status::OK,
+            result: 0,
+            length: 0,
+            slice_start: 0,
+            slice_end: 0,
+            default_value: 0,
+            io_index: 0,
+        }
+    }
+}
+
+impl<'a> Device for Memory<'a> {
+    fn kind(&self) -> u8 {
+        kind::MEMORY
+    }
+
+    fn read(&mut self, index: u32) -> Result<u8, DeviceError> {
+        self.register(index)
+    }
+
+    fn peek(&self, index: u32) -> Option<u8> {
+        self.register(index).ok()
+    }
+
+    fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError> {
+        if register_byte(index, register::RESULT, 4).is_some() {
+            return Err(DeviceError::ReadOnly(index));
+        }
+
+        let registers = [
+            (register::LENGTH, &mut self.length),
+            (register::SLICE_START, &mut self.slice_start),
+            (register::SLICE_END, &mut self.slice_end),
+        ];
+        for (start, register) in registers {
+            if let Some(byte) = register_byte(index, start, 4) {
+                set_register_byte(register, byte, value);
+                return Ok(());
+            }
+        }
 
-            register::COMMAND => self.run_command(value),
-            register::LENGTH => self.memory.resize(value as usize, self.default_value),
-            register::SLICE_START => self.slice_start = value,
-            register::SLICE_END => self.slice_end = value,
+        match index {
+            register::COMMAND => self.run_command(value)?,
             register::DEFAULT_VALUE => self.default_value = value,
             register::IO_INDEX => self.io_index = value,
 
-            _ => self.memory[index as usize - register::MEMORY] = value,
+            index if index >= register::MEMORY => {
+                *self
+                    .memory
+                    .get_mut((index - register::MEMORY) as usize)
+                    .ok_or(DeviceError::OutOfBounds(index))? = value
+            }
+
+            index => return Err(DeviceError::OutOfBounds(index)),
+        }
+
+        Ok(())
+    }
+
+    /// Registers followed by memory, leaving out inputs and outputs
+    fn snapshot(&self) -> Vec<u8> {
+        let mut snapshot = vec![self.status];
+        for register in [self.result, self.length, self.slice_start, self.slice_end] {
+            snapshot.extend(register.to_le_bytes());
         }
+        snapshot.extend([self.default_value, self.io_index]);
+        snapshot.extend(&self.memory);
+        snapshot
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader(snapshot);
+
+        let status = reader.u8()?;
+        let result = reader.u32()?;
+        let length = reader.u32()?;
+        let slice_start = reader.u32()?;
+        let slice_end = reader.u32()?;
+        let default_value = reader.u8()?;
+        let io_index = reader.u8()?;
+
+        self.status = status;
+        self.result = result;
+        self.length = length;
+        self.slice_start = slice_start;
+        self.slice_end = slice_end;
+        self.default_value = default_value;
+        self.io_index = io_index;
+        self.memory = reader.0.to_vec();
+
+        Ok(())
     }
 }
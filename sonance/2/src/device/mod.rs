@@ -1,48 +1,235 @@
+pub mod clock;
+mod error;
+pub mod filesystem;
+pub mod framebuffer;
 pub mod memory;
+pub mod random;
 
-use std::fmt::Debug;
+use std::{collections::BTreeMap, fmt::Debug};
+
+pub use crate::device::error::DeviceError;
+use crate::vm::{Integer, SnapshotError};
+
+/// Values returned by `Device::kind`, and pushed by `query_device`
+pub mod kind {
+    /// No device has the queried ID
+    pub const NONE: u8 = 0;
+    pub const MEMORY: u8 = 1;
+    pub const CLOCK: u8 = 2;
+    pub const RANDOM: u8 = 3;
+    pub const FILESYSTEM: u8 = 4;
+    pub const FRAMEBUFFER: u8 = 5;
+}
+
+/// Values of a device's command register after running a command
+pub mod status {
+    pub const OK: u8 = 0;
+    /// Input ended before the command finished
+    pub const END_OF_FILE: u8 = 1;
+    /// Any other IO error
+    pub const IO_ERROR: u8 = 2;
+    /// There's no input, output or file to use
+    pub const NO_STREAM: u8 = 3;
+    /// The path is invalid or outside of the sandbox
+    pub const INVALID_PATH: u8 = 4;
+}
 
 pub trait Device {
-    fn read(&mut self, index: u32) -> u8;
-    fn write(&mut self, index: u32, value: u8);
+    /// What sort of device this is, from `kind`, which must not be `kind::NONE`
+    fn kind(&self) -> u8;
+
+    /// Returning an error faults the VM, so save it for indexes that can never be valid
+    fn read(&mut self, index: u32) -> Result<u8, DeviceError>;
+    fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError>;
+
+    /// Read a register for inspection, like from the debugger, without any side effects
+    ///
+    /// `None` if there's no such register, or if reading it would change the device,
+    /// which is the default.
+    fn peek(&self, _index: u32) -> Option<u8> {
+        None
+    }
+
+    /// Encode any state that should survive `VM::snapshot`, defaulting to none
+    fn snapshot(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Replace this device's state with one from `snapshot`, leaving it untouched on error
+    fn restore(&mut self, _snapshot: &[u8]) -> Result<(), SnapshotError> {
+        Ok(())
+    }
 }
 
+/// The byte of a little endian register at `index`, if it's within the `size` byte register starting at `start`
+pub(crate) fn register_byte(index: u32, start: u32, size: u32) -> Option<usize> {
+    index
+        .checked_sub(start)
+        .filter(|byte| *byte < size)
+        .map(|byte| byte as usize)
+}
+
+/// Replace a single little endian byte of `register`
+pub(crate) fn set_register_byte<T: Integer>(register: &mut T, byte: usize, value: u8) {
+    let mut bytes = vec![];
+    register.push_le(&mut bytes);
+    bytes[byte] = value;
+    *register = T::from_le_slice(&bytes);
+}
+
+/// Owns every device attached to a VM, each under a stable ID
+///
+/// Writing to index `0` selects a device by ID, and reading it gives the selected ID.
+/// Every other index is passed on to the selected device.
 #[derive(Default)]
 pub struct DeviceManager<'a> {
-    devices: Vec<&'a mut dyn Device>,
+    devices: BTreeMap<u8, Box<dyn Device + 'a>>,
     selected: u8,
 }
 
 impl<'a> DeviceManager<'a> {
-    pub fn add<T: Device + 'a>(&mut self, device: &'a mut T) {
-        self.devices.push(device);
+    /// Attach a device under the lowest free ID, returning that ID
+    ///
+    /// # Panics
+    ///
+    /// If all 256 IDs are taken
+    pub fn add<T: Device + 'a>(&mut self, device: T) -> u8 {
+        let id = (0..=u8::MAX)
+            .find(|id| !self.devices.contains_key(id))
+            .expect("all device IDs are taken");
+
+        self.devices.insert(id, Box::new(device));
+        id
+    }
+
+    /// Attach a device under a specific ID, returning any device it replaced
+    pub fn insert<T: Device + 'a>(&mut self, id: u8, device: T) -> Option<Box<dyn Device + 'a>> {
+        self.devices.insert(id, Box::new(device))
+    }
+
+    pub fn remove(&mut self, id: u8) -> Option<Box<dyn Device + 'a>> {
+        self.devices.remove(&id)
+    }
+
+    pub fn get(&self, id: u8) -> Option<&(dyn Device + 'a)> {
+        self.devices.get(&id).map(|device| device.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: u8) -> Option<&mut (dyn Device + 'a)> {
+        self.devices.get_mut(&id).map(|device| device.as_mut())
+    }
+
+    /// The kind of the device with this ID, or `kind::NONE`
+    pub fn kind(&self, id: u8) -> u8 {
+        self.get(id).map_or(kind::NONE, |device| device.kind())
+    }
+
+    pub fn selected(&self) -> u8 {
+        self.selected
+    }
+
+    pub fn read(&mut self, index: u32) -> Result<u8, DeviceError> {
+        if index == 0 {
+            return Ok(self.selected);
+        }
+
+        self.selected_device()?.read(index)
     }
 
-    pub fn read(&mut self, index: u32) -> u8 {
+    /// Like `read`, but without side effects, and `None` for registers that can't be peeked
+    pub fn peek(&self, index: u32) -> Result<Option<u8>, DeviceError> {
         if index == 0 {
-            return self.selected;
+            return Ok(Some(self.selected));
         }
 
-        self.devices[self.selected as usize].read(index)
+        let device = self
+            .devices
+            .get(&self.selected)
+            .ok_or(DeviceError::NoSuchDevice(self.selected))?;
+        Ok(device.peek(index))
     }
 
-    pub fn write(&mut self, index: u32, value: u8) {
+    pub fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError> {
         if index == 0 {
-            return self.selected = value;
+            self.selected = value;
+            return Ok(());
+        }
+
+        self.selected_device()?.write(index, value)
+    }
+
+    fn selected_device(&mut self) -> Result<&mut Box<dyn Device + 'a>, DeviceError> {
+        self.devices
+            .get_mut(&self.selected)
+            .ok_or(DeviceError::NoSuchDevice(self.selected))
+    }
+
+    /// The selected device, and each device's snapshot by ID
+    pub fn snapshot(&self) -> (u8, BTreeMap<u8, Vec<u8>>) {
+        let devices = self
+            .devices
+            .iter()
+            .map(|(id, device)| (*id, device.snapshot()))
+            .collect();
+        (self.selected, devices)
+    }
+
+    pub fn restore(
+        &mut self,
+        selected: u8,
+        devices: &BTreeMap<u8, Vec<u8>>,
+    ) -> Result<(), SnapshotError> {
+        if devices.len() != self.devices.len() {
+            return Err(SnapshotError::DeviceCount(
+                self.devices.len(),
+                devices.len(),
+            ));
         }
 
-        self.devices[self.selected as usize].write(index, value);
+        if let Some(id) = devices.keys().find(|id| !self.devices.contains_key(id)) {
+            return Err(SnapshotError::NoSuchDevice(*id));
+        }
+
+        // Devices are restored one at a time, so if one fails, any before it are put back
+        let mut restored: Vec<(&u8, Vec<u8>)> = vec![];
+        for (id, snapshot) in devices {
+            let device = self.devices.get_mut(id).expect("checked every ID above");
+            let previous = device.snapshot();
+
+            if let Err(err) = device.restore(snapshot) {
+                for (id, previous) in restored {
+                    self.devices
+                        .get_mut(id)
+                        .expect("checked every ID above")
+                        .restore(&previous)
+                        .expect("a device should restore its own snapshot");
+                }
+                return Err(err);
+            }
+            restored.push((id, previous));
+        }
+        self.selected = selected;
+
+        Ok(())
     }
 }
 
 impl Debug for DeviceManager<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("DebugWrapper")
-            .field("devices", &self.devices.len())
+        let kinds: BTreeMap<_, _> = self
+            .devices
+            .iter()
+            .map(|(id, device)| (id, device.kind()))
+            .collect();
+
+        f.debug_struct("DeviceManager")
+            .field("devices", &kinds)
+            .field("selected", &self.selected)
             .finish()
     }
 }
 
+/// Devices can't be cloned, so clones start with none attached. Use `VM::snapshot` to copy device state.
 impl Clone for DeviceManager<'_> {
     fn clone(&self) -> Self {
         Self::default()
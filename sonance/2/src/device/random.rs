@@ -0,0 +1,116 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    device::{kind, register_byte, set_register_byte, Device, DeviceError},
+    program::Reader,
+    vm::SnapshotError,
+};
+
+/// A seedable pseudo-random number generator, which isn't suitable for cryptography
+///
+/// Runs with the same seed always give the same bytes.
+///
+/// - `1`: Random byte
+///     - Read: The next random byte
+///     - Write `0`: Reseed from Seed
+///
+/// - `2..4`: Reserved
+/// - `4..12`: Seed as a `u64`
+///
+#[derive(Debug, Clone)]
+pub struct Random {
+    seed: u64,
+    state: u64,
+}
+
+mod register {
+    pub const RANDOM: u32 = 1;
+    pub const SEED: u32 = 4;
+}
+
+mod command {
+    pub const RESEED: u8 = 0;
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, state: seed }
+    }
+
+    /// Seeded from the system time, so every run is different
+    pub fn from_time() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::new(now.as_nanos() as u64)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// SplitMix64, which is fast and has no bad seeds
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Device for Random {
+    fn kind(&self) -> u8 {
+        kind::RANDOM
+    }
+
+    fn read(&mut self, index: u32) -> Result<u8, DeviceError> {
+        if let Some(byte) = register_byte(index, register::SEED, 8) {
+            return Ok(self.seed.to_le_bytes()[byte]);
+        }
+
+        match index {
+            register::RANDOM => Ok((self.next_u64() >> 56) as u8),
+            index => Err(DeviceError::OutOfBounds(index)),
+        }
+    }
+
+    /// Only the seed, since reading a random number moves on to the next one
+    fn peek(&self, index: u32) -> Option<u8> {
+        register_byte(index, register::SEED, 8).map(|byte| self.seed.to_le_bytes()[byte])
+    }
+
+    fn write(&mut self, index: u32, value: u8) -> Result<(), DeviceError> {
+        if let Some(byte) = register_byte(index, register::SEED, 8) {
+            set_register_byte(&mut self.seed, byte, value);
+            return Ok(());
+        }
+
+        match (index, value) {
+            (register::RANDOM, command::RESEED) => self.state = self.seed,
+            (register::RANDOM, command) => return Err(DeviceError::InvalidCommand(command)),
+            (index, _) => return Err(DeviceError::OutOfBounds(index)),
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut snapshot = self.seed.to_le_bytes().to_vec();
+        snapshot.extend(self.state.to_le_bytes());
+        snapshot
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader(snapshot);
+
+        let seed = reader.u64()?;
+        let state = reader.u64()?;
+
+        self.seed = seed;
+        self.state = state;
+
+        Ok(())
+    }
+}
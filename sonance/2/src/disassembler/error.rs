@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+pub type Result<T, E = DisassembleError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Error)]
+pub enum DisassembleError {
+    #[error("{0} at index {1} is not a valid instruction")]
+    InvalidInstruction(u8, u32),
+    #[error("instruction at index {0} is missing part of its operand")]
+    UnexpectedEnd(u32),
+}
@@ -0,0 +1,230 @@
+mod error;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+pub use crate::disassembler::error::{DisassembleError, Result};
+use crate::{instruction::Instruction, program::Program};
+
+/// A decoded instruction along with its inline operand, if it's a push
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decoded {
+    pub address: u32,
+    pub instruction: Instruction,
+    /// Little endian operand of `Push`, `PushU16`, `PushU32` or `PushU64`
+    pub operand: Option<u64>,
+}
+
+fn operand_len(instruction: Instruction) -> usize {
+    match instruction {
+        Instruction::Push => 1,
+        Instruction::PushU16 => 2,
+        Instruction::PushU32 => 4,
+        Instruction::PushU64 => 8,
+        _ => 0,
+    }
+}
+
+/// Decode every instruction in `code`
+pub fn decode(code: &[u8]) -> Result<Vec<Decoded>> {
+    let mut decoded = vec![];
+    let mut address = 0;
+
+    while address < code.len() {
+        let code_byte = code[address];
+        let instruction = Instruction::try_from(code_byte)
+            .map_err(|_| DisassembleError::InvalidInstruction(code_byte, address as u32))?;
+
+        let len = operand_len(instruction);
+        let operand = match len {
+            0 => None,
+            len => {
+                let bytes = code
+                    .get(address + 1..address + 1 + len)
+                    .ok_or(DisassembleError::UnexpectedEnd(address as u32))?;
+
+                let mut le = [0; 8];
+                le[..len].copy_from_slice(bytes);
+                Some(u64::from_le_bytes(le))
+            }
+        };
+
+        decoded.push(Decoded {
+            address: address as u32,
+            instruction,
+            operand,
+        });
+        address += 1 + len;
+    }
+
+    Ok(decoded)
+}
+
+/// Turn a program back into source that `parser::parse` assembles to the same code and data
+///
+/// `u32` pushes right before a `jump`, `jump_if` or `call` become label references,
+/// right before a `load_data` become data references, and right before a global
+/// or indirect load or store become global references, using the program's own
+/// names where it has them.
+pub fn disassemble(program: &Program) -> Result<String> {
+    let decoded = decode(&program.code)?;
+
+    let boundaries: BTreeSet<u32> = decoded
+        .iter()
+        .map(|decoded| decoded.address)
+        .chain([program.code.len() as u32])
+        .collect();
+
+    let mut labels: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for (name, address) in &program.labels {
+        if boundaries.contains(address) {
+            labels.entry(*address).or_default().push(name.clone());
+        }
+    }
+
+    let data_labels: BTreeMap<u32, &str> = program
+        .data_labels
+        .iter()
+        .filter(|(_, offset)| **offset as usize <= program.data.len())
+        .map(|(name, offset)| (*offset, name.as_str()))
+        .collect();
+
+    let globals = global_starts(program);
+
+    let mut lines = Vec::with_capacity(decoded.len());
+    for (index, current) in decoded.iter().enumerate() {
+        let next = decoded.get(index + 1).map(|next| next.instruction);
+
+        let line = match (current.instruction, current.operand, next) {
+            (
+                Instruction::PushU32,
+                Some(target),
+                Some(Instruction::Jump | Instruction::JumpIf | Instruction::Call),
+            ) if boundaries.contains(&(target as u32)) => {
+                let names = labels.entry(target as u32).or_default();
+                if names.is_empty() {
+                    names.push(unused_name(format!("label_{target}"), &program.labels));
+                }
+                format!("push #{}", names[0])
+            }
+            (Instruction::PushU32, Some(offset), Some(Instruction::LoadData))
+                if data_labels.contains_key(&(offset as u32)) =>
+            {
+                format!("push ${}", data_labels[&(offset as u32)])
+            }
+            (
+                Instruction::PushU32,
+                Some(address),
+                Some(
+                    Instruction::LoadGlobal
+                    | Instruction::StoreGlobal
+                    | Instruction::LoadIndirect
+                    | Instruction::StoreIndirect,
+                ),
+            ) if globals.contains_key(&(address as u32)) => {
+                format!("push @{}", globals[&(address as u32)])
+            }
+            (Instruction::Push, Some(value), _) => format!("push {value}"),
+            (Instruction::PushU16, Some(value), _) => format!("push {value}u16"),
+            (Instruction::PushU32, Some(value), _) => format!("push {value}u32"),
+            (Instruction::PushU64, Some(value), _) => format!("push {value}u64"),
+            (instruction, _, _) => instruction.to_string(),
+        };
+
+        lines.push((current.address, line));
+    }
+
+    let mut output = String::new();
+    write_data(program, &mut output);
+    write_globals(program, &globals, &mut output);
+
+    for (address, line) in lines {
+        write_labels(&labels, address, &mut output);
+        writeln!(output, "{line}").expect("writing to a String should never fail");
+    }
+    write_labels(&labels, program.code.len() as u32, &mut output);
+
+    Ok(output)
+}
+
+fn write_labels(labels: &BTreeMap<u32, Vec<String>>, address: u32, output: &mut String) {
+    for name in labels.get(&address).into_iter().flatten() {
+        writeln!(output, "#{name}").expect("writing to a String should never fail");
+    }
+}
+
+/// Split the data section at each data label, writing each piece as a `data` directive
+fn write_data(program: &Program, output: &mut String) {
+    if program.data.is_empty() && program.data_labels.is_empty() {
+        return;
+    }
+
+    let first = unused_name("data_0".to_owned(), &program.data_labels);
+    let mut starts: BTreeMap<u32, &str> = BTreeMap::from([(0, first.as_str())]);
+    for (name, offset) in &program.data_labels {
+        if *offset as usize <= program.data.len() {
+            starts.insert(*offset, name);
+        }
+    }
+
+    let starts: Vec<_> = starts.into_iter().collect();
+    for (index, (start, name)) in starts.iter().enumerate() {
+        let end = starts
+            .get(index + 1)
+            .map_or(program.data.len(), |(end, _)| *end as usize);
+
+        write!(output, "data {name}").expect("writing to a String should never fail");
+        for byte in &program.data[*start as usize..end] {
+            write!(output, " {byte}").expect("writing to a String should never fail");
+        }
+        writeln!(output).expect("writing to a String should never fail");
+    }
+    writeln!(output).expect("writing to a String should never fail");
+}
+
+/// The name to give the global at each address, covering the whole heap
+fn global_starts(program: &Program) -> BTreeMap<u32, String> {
+    let mut starts = BTreeMap::new();
+    if program.heap_size > 0 {
+        starts.insert(0, unused_name("global_0".to_owned(), &program.globals));
+    }
+
+    for (name, address) in &program.globals {
+        if *address < program.heap_size {
+            starts.insert(*address, name.clone());
+        }
+    }
+    starts
+}
+
+/// `name`, or `name` with a number after it if the program already uses it for something else
+fn unused_name(name: String, used: &BTreeMap<String, u32>) -> String {
+    if !used.contains_key(&name) {
+        return name;
+    }
+
+    (1..)
+        .map(|suffix| format!("{name}_{suffix}"))
+        .find(|name| !used.contains_key(name))
+        .expect("there should always be an unused suffix")
+}
+
+/// Write each global as a `global` directive, sized up to the next one
+fn write_globals(program: &Program, starts: &BTreeMap<u32, String>, output: &mut String) {
+    if starts.is_empty() {
+        return;
+    }
+
+    let starts: Vec<_> = starts.iter().collect();
+    for (index, (start, name)) in starts.iter().enumerate() {
+        let end = starts
+            .get(index + 1)
+            .map_or(program.heap_size, |(end, _)| **end);
+
+        writeln!(output, "global {name} {}", end - **start)
+            .expect("writing to a String should never fail");
+    }
+    writeln!(output).expect("writing to a String should never fail");
+}
@@ -1,11 +1,10 @@
-use casey::snake;
 use std::{fmt::Display, str::FromStr};
 
 use crate::parser::ParseError;
 
 macro_rules! generate_instructions {
-    (enum $ty:ident { $($ident:ident,)* }) => {
-        #[derive(Debug, Clone, Copy, PartialEq)]
+    (enum $ty:ident { $($ident:ident = $name:literal,)* }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
         pub enum $ty {
             $($ident,)*
         }
@@ -29,20 +28,27 @@ macro_rules! generate_instructions {
             }
         }
 
-        impl Display for $ty {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        impl $ty {
+            /// Mnemonic used in assembly, like `push_u16`
+            pub fn name(self) -> &'static str {
                 match self {
-                    $($ty::$ident => write!(f, snake!(stringify!($ident))),)*
+                    $($ty::$ident => $name,)*
                 }
             }
         }
 
+        impl Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
         impl FromStr for $ty {
             type Err = ParseError;
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 Ok(match s {
-                    $(snake!(stringify!($ident)) => $ty::$ident,)*
+                    $($name => $ty::$ident,)*
                     _ => return Err(ParseError::InvalidInstruction(s.into())),
                 })
             }
@@ -52,44 +58,105 @@ macro_rules! generate_instructions {
 
 generate_instructions! {
     enum Instruction {
-        Halt,
-        Debug,
-        Noop,
-
-        Push,
-        PushU16,
-        PushU32,
-        PushU64,
-        Pop,
-        Dupe,
-
-        Jump,
-        JumpIf,
-
-        Load,
-        Store,
-
-        Read,
-        Write,
-
-        Call,
-        Return,
-
-        Add,
-        Sub,
-        Mul,
-        Div,
-
-        BitAnd,
-        BitOr,
-        BitNot,
-
-        BoolAnd,
-        BoolOr,
-        BoolNot,
-
-        Eq,
-        Gt,
-        Geq,
+        Halt = "halt",
+        Debug = "debug",
+        Noop = "noop",
+
+        Push = "push",
+        PushU16 = "push_u16",
+        PushU32 = "push_u32",
+        PushU64 = "push_u64",
+        Pop = "pop",
+        Dupe = "dupe",
+
+        Jump = "jump",
+        JumpIf = "jump_if",
+
+        Load = "load",
+        Store = "store",
+        LoadData = "load_data",
+
+        Read = "read",
+        Write = "write",
+
+        Call = "call",
+        Return = "return",
+
+        Add = "add",
+        Sub = "sub",
+        Mul = "mul",
+        Div = "div",
+        Overflow = "overflow",
+
+        BitAnd = "bit_and",
+        BitOr = "bit_or",
+        BitNot = "bit_not",
+
+        BoolAnd = "bool_and",
+        BoolOr = "bool_or",
+        BoolNot = "bool_not",
+
+        Eq = "eq",
+        Gt = "gt",
+        Geq = "geq",
+
+        AddU16 = "add_u16",
+        SubU16 = "sub_u16",
+        MulU16 = "mul_u16",
+        DivU16 = "div_u16",
+        EqU16 = "eq_u16",
+        GtU16 = "gt_u16",
+        GeqU16 = "geq_u16",
+
+        AddU32 = "add_u32",
+        SubU32 = "sub_u32",
+        MulU32 = "mul_u32",
+        DivU32 = "div_u32",
+        EqU32 = "eq_u32",
+        GtU32 = "gt_u32",
+        GeqU32 = "geq_u32",
+
+        AddU64 = "add_u64",
+        SubU64 = "sub_u64",
+        MulU64 = "mul_u64",
+        DivU64 = "div_u64",
+        EqU64 = "eq_u64",
+        GtU64 = "gt_u64",
+        GeqU64 = "geq_u64",
+
+        AddI8 = "add_i8",
+        SubI8 = "sub_i8",
+        MulI8 = "mul_i8",
+        DivI8 = "div_i8",
+        GtI8 = "gt_i8",
+        GeqI8 = "geq_i8",
+
+        AddI16 = "add_i16",
+        SubI16 = "sub_i16",
+        MulI16 = "mul_i16",
+        DivI16 = "div_i16",
+        GtI16 = "gt_i16",
+        GeqI16 = "geq_i16",
+
+        AddI32 = "add_i32",
+        SubI32 = "sub_i32",
+        MulI32 = "mul_i32",
+        DivI32 = "div_i32",
+        GtI32 = "gt_i32",
+        GeqI32 = "geq_i32",
+
+        AddI64 = "add_i64",
+        SubI64 = "sub_i64",
+        MulI64 = "mul_i64",
+        DivI64 = "div_i64",
+        GtI64 = "gt_i64",
+        GeqI64 = "geq_i64",
+
+        QueryDevice = "query_device",
+        LoadGlobal = "load_global",
+        StoreGlobal = "store_global",
+        LoadIndirect = "load_indirect",
+        StoreIndirect = "store_indirect",
+        Reserve = "reserve",
     }
 }
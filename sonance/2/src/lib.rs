@@ -1,7 +1,9 @@
-pub mod assembler;
+pub mod debugger;
 pub mod device;
+pub mod disassembler;
 pub mod instruction;
 pub mod parser;
+pub mod program;
 #[cfg(test)]
 mod test;
 pub mod vm;
@@ -1,23 +1,188 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
-use clap::Parser;
-use sonance::{device::memory::Memory, parser, vm::VM};
+use clap::{Parser, Subcommand, ValueEnum};
+use sonance::{
+    debugger::Debugger,
+    device::{
+        clock::Clock,
+        filesystem::Filesystem,
+        framebuffer::{Format, Framebuffer},
+        memory::Memory,
+        random::Random,
+    },
+    disassembler, parser,
+    program::Program,
+    vm::{Arithmetic, Trace, VM},
+};
 
 #[derive(Parser)]
 struct Args {
-    input: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble a program into bytecode
+    Build {
+        input: PathBuf,
+        /// Defaults to the input with a `.snc` extension
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Step through a program interactively
+    Debug {
+        input: PathBuf,
+        #[clap(flatten)]
+        devices: Devices,
+    },
+    /// Print bytecode as source
+    Disassemble { input: PathBuf },
+    /// Run a program, either as source or bytecode
+    Run {
+        input: PathBuf,
+        /// Stop with an error on arithmetic overflow instead of wrapping
+        #[clap(long)]
+        checked: bool,
+        /// Print a trace to stderr, as the program runs for `log` or once it stops for `counts`
+        #[clap(long, value_enum)]
+        trace: Option<TraceMode>,
+        #[clap(flatten)]
+        devices: Devices,
+    },
+}
+
+/// Devices to attach, in order: memory, clock, random, then the filesystem and framebuffer if enabled
+#[derive(clap::Args)]
+struct Devices {
+    /// Seed for the random device, instead of the system time
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Attach a filesystem device sandboxed to this directory
+    #[clap(long)]
+    root: Option<PathBuf>,
+    /// Attach a framebuffer device, presenting frames to this file one image after another
+    #[clap(long)]
+    frames: Option<PathBuf>,
+    /// How to encode presented frames
+    #[clap(long, value_enum, default_value = "ppm")]
+    frame_format: FrameFormat,
+}
+
+impl Devices {
+    fn attach(&self, vm: &mut VM) -> Result<()> {
+        vm.add_device(Memory::standard_io());
+        vm.add_device(Clock::new());
+        vm.add_device(self.seed.map_or_else(Random::from_time, Random::new));
+
+        if let Some(root) = &self.root {
+            vm.add_device(Filesystem::new(root)?);
+        }
+        if let Some(frames) = &self.frames {
+            let mut framebuffer = Framebuffer::new(0, 0);
+            framebuffer.set_output(BufWriter::new(File::create(frames)?));
+            framebuffer.set_format(match self.frame_format {
+                FrameFormat::Ppm => Format::Ppm,
+                FrameFormat::Png => Format::Png,
+            });
+            vm.add_device(framebuffer);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FrameFormat {
+    /// Binary PPM, which ffmpeg can read as a video stream
+    Ppm,
+    /// Uncompressed PNG, failing on empty frames
+    Png,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TraceMode {
+    /// Every instruction run, with its address and stack depth
+    Log,
+    /// How many times each label, instruction and address was run
+    Counts,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    let src = std::fs::read_to_string(args.input)?;
-    let instructions = parser::parse(&src)?;
+    match Args::parse().command {
+        Command::Build { input, output } => {
+            let program = parser::parse(&std::fs::read_to_string(&input)?)?;
+            let output = output.unwrap_or_else(|| input.with_extension("snc"));
+            std::fs::write(output, program.encode())?;
+        }
+        Command::Debug { input, devices } => {
+            let program = load(&input)?;
+            let mut vm = VM::new(program.clone());
+            devices.attach(&mut vm)?;
+
+            let mut debugger = Debugger::new(vm, &program);
+            let mut stdout = std::io::stdout();
+            let mut line = String::new();
+
+            loop {
+                print!("(sonance) ");
+                stdout.flush()?;
+
+                line.clear();
+                if std::io::stdin().read_line(&mut line)? == 0
+                    || !debugger.command(&line, &mut stdout)?
+                {
+                    break;
+                }
+            }
+        }
+        Command::Disassemble { input } => {
+            print!("{}", disassembler::disassemble(&load(&input)?)?);
+        }
+        Command::Run {
+            input,
+            checked,
+            trace,
+            devices,
+        } => {
+            let program = load(&input)?;
+            let mut vm = VM::new(program.clone());
+            if checked {
+                vm.arithmetic = Arithmetic::Checked;
+            }
+            vm.trace = trace.map(|trace| match trace {
+                TraceMode::Log => Trace::log(BufWriter::new(std::io::stderr())),
+                TraceMode::Counts => Trace::counts(),
+            });
+            devices.attach(&mut vm)?;
 
-    let mut memory = Memory::standard_io();
-    let mut vm = VM::new(instructions);
-    vm.add_device(&mut memory);
-    vm.run()?;
+            let result = vm.run();
+            match (trace, &mut vm.trace) {
+                (Some(TraceMode::Log), Some(trace)) => trace.flush()?,
+                (Some(TraceMode::Counts), Some(trace)) => {
+                    eprint!("{}", trace.report(&program.labels))
+                }
+                _ => {}
+            }
+            result?;
+        }
+    }
 
     Ok(())
 }
+
+/// Load bytecode, or assemble it if `path` is source
+fn load(path: &Path) -> Result<Program> {
+    let bytes = std::fs::read(path)?;
+
+    if Program::is_encoded(&bytes) {
+        return Ok(Program::decode(&bytes)?);
+    }
+
+    Ok(parser::parse(&String::from_utf8(bytes)?)?)
+}
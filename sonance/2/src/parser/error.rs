@@ -2,6 +2,8 @@ use std::num::ParseIntError;
 
 use thiserror::Error;
 
+use crate::program::MAX_HEAP_SIZE;
+
 pub type Result<T, E = ParseError> = std::result::Result<T, E>;
 
 #[derive(Debug, Clone, Error)]
@@ -12,4 +14,25 @@ pub enum ParseError {
     InvalidConstant(#[from] ParseIntError),
     #[error("label {0} not found")]
     LabelNotFound(String),
+    #[error("data {0} not found")]
+    DataNotFound(String),
+    #[error("data directive is missing a name")]
+    MissingDataName,
+    #[error("global {0} not found")]
+    GlobalNotFound(String),
+    #[error("global directive is missing a name")]
+    MissingGlobalName,
+    #[error("global {0} is already defined")]
+    DuplicateGlobal(String),
+    #[error(
+        "globals take up more than the maximum of {} bytes of heap",
+        MAX_HEAP_SIZE
+    )]
+    HeapTooLarge,
+    #[error("locals is missing an end before the next function or the end of the source")]
+    MissingEnd,
+    #[error("end without a locals to close")]
+    UnmatchedEnd,
+    #[error("more than 65535 variables")]
+    TooManyVariables,
 }
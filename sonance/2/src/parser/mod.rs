@@ -2,31 +2,71 @@ mod error;
 
 use std::collections::HashMap;
 
-use crate::instruction::Instruction;
 pub use crate::parser::error::{ParseError, Result};
+use crate::{
+    instruction::Instruction,
+    program::{Program, MAX_HEAP_SIZE},
+};
 
 #[derive(Debug, Clone)]
 enum Item {
     Instruction(Instruction),
     Raw(u8),
+    /// The address of a label as a little endian `u32`
     LabelReference(String),
+    /// The offset of a data entry as a little endian `u32`
+    DataReference(String),
+    /// The heap address of a global as a little endian `u32`
+    GlobalReference(String),
+    /// The number of slots a scope uses as a little endian `u16`
+    SlotCount(usize),
+}
+
+impl Item {
+    /// Number of bytes this item will take up once built
+    fn len(&self) -> u32 {
+        match self {
+            Item::Instruction(_) | Item::Raw(_) => 1,
+            Item::SlotCount(_) => 2,
+            Item::LabelReference(_) | Item::DataReference(_) | Item::GlobalReference(_) => 4,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 struct InstructionParser {
     items: Vec<Item>,
-    labels: HashMap<String, u8>,
-    variables: HashMap<String, u8>,
-    variable_counter: u8,
+    /// Length in bytes of every item so far
+    len: u32,
+    labels: HashMap<String, u32>,
+    data: Vec<u8>,
+    data_labels: HashMap<String, u32>,
+    heap_size: u32,
+    globals: HashMap<String, u32>,
+    /// Slot of each variable in the current scope
+    variables: HashMap<String, u16>,
+    /// Variables of the top level, set aside between `locals` and `end`
+    top_level: Option<HashMap<String, u16>>,
+    /// Number of slots used by each scope so far, the first being the top level
+    scopes: Vec<u16>,
+    /// Index in `scopes` of the current scope
+    scope: usize,
 }
 
-pub fn parse(src: &str) -> Result<Vec<u8>> {
-    let mut parser = InstructionParser::default();
+pub fn parse(src: &str) -> Result<Program> {
+    let mut parser = InstructionParser {
+        scopes: vec![0],
+        ..Default::default()
+    };
 
     for line in src.lines() {
         parser.parse_line(line)?;
     }
 
+    if parser.top_level.is_some() {
+        return Err(ParseError::MissingEnd);
+    }
+
     parser.build()
 }
 
@@ -43,119 +83,324 @@ impl InstructionParser {
         }
 
         if let Some(label) = line.strip_prefix('#') {
-            self.labels.insert(label.to_owned(), self.items.len() as _);
+            self.labels.insert(label.to_owned(), self.len);
             return Ok(());
         }
 
-        let mut args = line.split_whitespace();
-        let instruction_str = args
-            .next()
-            .expect("split should always give at least one str");
+        let mut args = split_args(line);
+        let instruction_str = args.remove(0);
+
+        if instruction_str == "data" {
+            return self.parse_data(&args);
+        }
+        if instruction_str == "global" {
+            return self.parse_global(&args);
+        }
+        if instruction_str == "locals" {
+            return self.parse_locals(&args);
+        }
+        if instruction_str == "end" {
+            return self.parse_end();
+        }
 
-        for arg in args.rev() {
+        for arg in args.into_iter().rev() {
             self.parse_arg(arg)?;
         }
 
         let instruction = instruction_str.parse()?;
         if !instruction_str.starts_with("push") {
-            self.items.push(Item::Instruction(instruction));
+            self.push(Item::Instruction(instruction));
         }
 
         Ok(())
     }
 
+    fn push(&mut self, item: Item) {
+        self.len += item.len();
+        self.items.push(item);
+    }
+
+    fn push_bytes(&mut self, instruction: Instruction, bytes: &[u8]) {
+        self.push(Item::Instruction(instruction));
+        for &raw in bytes {
+            self.push(Item::Raw(raw));
+        }
+    }
+
     fn parse_arg(&mut self, arg: &str) -> Result<()> {
         if let Some(label) = arg.strip_prefix('#') {
-            self.items.push(Item::Instruction(Instruction::Push));
-            self.items.push(Item::LabelReference(label.to_owned()));
+            self.push(Item::Instruction(Instruction::PushU32));
+            self.push(Item::LabelReference(label.to_owned()));
+            return Ok(());
+        }
+
+        if let Some(name) = arg.strip_prefix('$') {
+            self.push(Item::Instruction(Instruction::PushU32));
+            self.push(Item::DataReference(name.to_owned()));
+            return Ok(());
+        }
+
+        if let Some(name) = arg.strip_prefix('@') {
+            self.push(Item::Instruction(Instruction::PushU32));
+            self.push(Item::GlobalReference(name.to_owned()));
             return Ok(());
         }
 
         if let Some(variable) = arg.strip_prefix('&') {
-            let variable = *self
-                .variables
-                .entry(variable.to_owned())
-                .or_insert_with(|| {
-                    let variable = self.variable_counter;
-                    self.variable_counter += 1;
-                    variable
-                });
-
-            self.items.push(Item::Instruction(Instruction::Push));
-            self.items.push(Item::Raw(variable));
+            let variable = self.variable(variable)?;
+            self.push_bytes(Instruction::PushU16, &variable.to_le_bytes());
             return Ok(());
         }
 
-        if let Some(char) = arg
-            .strip_prefix('\'')
-            .and_then(|arg| arg.strip_suffix('\''))
-        {
-            self.items.push(Item::Instruction(Instruction::Push));
+        let (instruction, bytes) = parse_literal(arg)?;
+        self.push_bytes(instruction, &bytes);
+
+        Ok(())
+    }
 
-            let char = match char {
-                "\\n" => b'\n',
-                char => char.chars().next().unwrap_or(' ') as u8,
-            };
+    /// `data name "string" 'c' 1u32 ...`, appending each value to the data section
+    fn parse_data(&mut self, args: &[&str]) -> Result<()> {
+        let (name, values) = args.split_first().ok_or(ParseError::MissingDataName)?;
+        self.data_labels
+            .insert((*name).to_owned(), self.data.len() as u32);
 
-            self.items.push(Item::Raw(char));
-            return Ok(());
+        for value in values {
+            match value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+            {
+                Some(string) => self.data.extend(unescape(string).bytes()),
+                None => self.data.extend(parse_literal(value)?.1),
+            }
         }
 
-        let arg = &arg.replace('_', "");
+        Ok(())
+    }
 
-        if let Some(number) = arg.strip_suffix("u8") {
-            let number = number.parse()?;
-            self.items.push(Item::Instruction(Instruction::Push));
-            self.items.push(Item::Raw(number));
-            return Ok(());
+    /// The slot of a variable in the current scope, allocating the next one if it's new
+    fn variable(&mut self, name: &str) -> Result<u16> {
+        if let Some(variable) = self.variables.get(name) {
+            return Ok(*variable);
         }
 
-        if let Some(number) = arg.strip_suffix("u16") {
-            let number: u16 = number.parse()?;
-            self.items.push(Item::Instruction(Instruction::PushU16));
-            for raw in number.to_le_bytes() {
-                self.items.push(Item::Raw(raw));
-            }
-            return Ok(());
+        let slots = &mut self.scopes[self.scope];
+        let variable = *slots;
+        *slots = variable
+            .checked_add(1)
+            .ok_or(ParseError::TooManyVariables)?;
+        self.variables.insert(name.to_owned(), variable);
+
+        Ok(variable)
+    }
+
+    /// `locals &arg ...`, a function prologue that starts a new scope whose variables get
+    /// slots from zero, and reserves however many slots the scope ends up using
+    ///
+    /// Arguments are named in the order `call` puts them in slots, so the first inline
+    /// argument to `call` after the arity is the first one here. The scope lasts until
+    /// `end`, and functions can't be nested.
+    fn parse_locals(&mut self, args: &[&str]) -> Result<()> {
+        if self.top_level.is_some() {
+            return Err(ParseError::MissingEnd);
         }
 
-        if let Some(number) = arg.strip_suffix("u32") {
-            let number: u32 = number.parse()?;
-            self.items.push(Item::Instruction(Instruction::PushU32));
-            for raw in number.to_le_bytes() {
-                self.items.push(Item::Raw(raw));
-            }
-            return Ok(());
+        self.push(Item::Instruction(Instruction::PushU16));
+        self.push(Item::SlotCount(self.scopes.len()));
+        self.push(Item::Instruction(Instruction::Reserve));
+
+        self.top_level = Some(std::mem::take(&mut self.variables));
+        self.scope = self.scopes.len();
+        self.scopes.push(0);
+
+        for arg in args {
+            self.variable(arg.strip_prefix('&').unwrap_or(arg))?;
         }
 
-        if let Some(number) = arg.strip_suffix("u64") {
-            let number: u64 = number.parse()?;
-            self.items.push(Item::Instruction(Instruction::PushU64));
-            for raw in number.to_le_bytes() {
-                self.items.push(Item::Raw(raw));
+        Ok(())
+    }
+
+    /// `end`, closing the scope started by `locals` and going back to the top level one
+    fn parse_end(&mut self) -> Result<()> {
+        self.variables = self.top_level.take().ok_or(ParseError::UnmatchedEnd)?;
+        self.scope = 0;
+
+        Ok(())
+    }
+
+    /// `global name [size]`, reserving `size` bytes of heap, defaulting to one
+    fn parse_global(&mut self, args: &[&str]) -> Result<()> {
+        let (name, size) = match args {
+            [name] => (name, 1),
+            [name, size] => (name, size.replace('_', "").parse()?),
+            _ => return Err(ParseError::MissingGlobalName),
+        };
+
+        if self.globals.contains_key(*name) {
+            return Err(ParseError::DuplicateGlobal((*name).to_owned()));
+        }
+        self.globals.insert((*name).to_owned(), self.heap_size);
+        self.heap_size = self
+            .heap_size
+            .checked_add(size)
+            .filter(|&heap_size| heap_size <= MAX_HEAP_SIZE)
+            .ok_or(ParseError::HeapTooLarge)?;
+
+        Ok(())
+    }
+
+    fn build(self) -> Result<Program> {
+        let mut code = Vec::with_capacity(self.len as usize);
+
+        for item in self.items {
+            match item {
+                Item::Instruction(instruction) => code.push(instruction as u8),
+                Item::Raw(raw) => code.push(raw),
+                Item::LabelReference(label) => {
+                    let index = self
+                        .labels
+                        .get(&label)
+                        .ok_or(ParseError::LabelNotFound(label))?;
+                    code.extend(index.to_le_bytes());
+                }
+                Item::DataReference(name) => {
+                    let offset = self
+                        .data_labels
+                        .get(&name)
+                        .ok_or(ParseError::DataNotFound(name))?;
+                    code.extend(offset.to_le_bytes());
+                }
+                Item::SlotCount(scope) => code.extend(self.scopes[scope].to_le_bytes()),
+                Item::GlobalReference(name) => {
+                    let address = self
+                        .globals
+                        .get(&name)
+                        .ok_or(ParseError::GlobalNotFound(name))?;
+                    code.extend(address.to_le_bytes());
+                }
             }
-            return Ok(());
         }
 
-        let number = arg.parse()?;
-        self.items.push(Item::Instruction(Instruction::Push));
-        self.items.push(Item::Raw(number));
+        Ok(Program {
+            code,
+            data: self.data,
+            labels: self.labels.into_iter().collect(),
+            data_labels: self.data_labels.into_iter().collect(),
+            heap_size: self.heap_size,
+            globals: self.globals.into_iter().collect(),
+        })
+    }
+}
+
+/// A char or number, along with the push instruction for its width
+fn parse_literal(arg: &str) -> Result<(Instruction, Vec<u8>)> {
+    if let Some(char) = arg
+        .strip_prefix('\'')
+        .and_then(|arg| arg.strip_suffix('\''))
+    {
+        let char = unescape(char).bytes().next().unwrap_or(b' ');
+        return Ok((Instruction::Push, vec![char]));
+    }
 
-        Ok(())
+    let arg = &arg.replace('_', "");
+
+    if let Some(number) = arg.strip_suffix("u8") {
+        let number: u8 = number.parse()?;
+        return Ok((Instruction::Push, number.to_le_bytes().to_vec()));
+    }
+
+    if let Some(number) = arg.strip_suffix("u16") {
+        let number: u16 = number.parse()?;
+        return Ok((Instruction::PushU16, number.to_le_bytes().to_vec()));
+    }
+
+    if let Some(number) = arg.strip_suffix("u32") {
+        let number: u32 = number.parse()?;
+        return Ok((Instruction::PushU32, number.to_le_bytes().to_vec()));
+    }
+
+    if let Some(number) = arg.strip_suffix("u64") {
+        let number: u64 = number.parse()?;
+        return Ok((Instruction::PushU64, number.to_le_bytes().to_vec()));
+    }
+
+    if let Some(number) = arg.strip_suffix("i8") {
+        let number: i8 = number.parse()?;
+        return Ok((Instruction::Push, number.to_le_bytes().to_vec()));
     }
 
-    fn build(self) -> Result<Vec<u8>> {
-        self.items
-            .into_iter()
-            .map(|item| match item {
-                Item::Instruction(instruction) => Ok(instruction as u8),
-                Item::Raw(raw) => Ok(raw),
-                Item::LabelReference(label) => self
-                    .labels
-                    .get(&label)
-                    .map(|index| index - 1)
-                    .ok_or(ParseError::LabelNotFound(label)),
-            })
-            .collect()
+    if let Some(number) = arg.strip_suffix("i16") {
+        let number: i16 = number.parse()?;
+        return Ok((Instruction::PushU16, number.to_le_bytes().to_vec()));
     }
+
+    if let Some(number) = arg.strip_suffix("i32") {
+        let number: i32 = number.parse()?;
+        return Ok((Instruction::PushU32, number.to_le_bytes().to_vec()));
+    }
+
+    if let Some(number) = arg.strip_suffix("i64") {
+        let number: i64 = number.parse()?;
+        return Ok((Instruction::PushU64, number.to_le_bytes().to_vec()));
+    }
+
+    let number: u8 = arg.parse()?;
+    Ok((Instruction::Push, vec![number]))
+}
+
+fn unescape(src: &str) -> String {
+    let mut output = String::with_capacity(src.len());
+    let mut chars = src.chars();
+
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            output.push(char);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some('0') => output.push('\0'),
+            Some(char) => output.push(char),
+            None => output.push('\\'),
+        }
+    }
+
+    output
+}
+
+/// Split a line on whitespace, keeping quoted literals like `' '` together
+fn split_args(line: &str) -> Vec<&str> {
+    let mut args = vec![];
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        let end = match rest.chars().next() {
+            Some(quote @ ('\'' | '"')) => {
+                closing_quote(&rest[1..], quote).map_or(rest.len(), |end| end + 2)
+            }
+            _ => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+        };
+
+        args.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+
+    args
+}
+
+/// Index of the first unescaped `quote` in `src`
+fn closing_quote(src: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+
+    for (index, char) in src.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            char if char == quote => return Some(index),
+            _ => {}
+        }
+    }
+
+    None
 }
@@ -0,0 +1,32 @@
+use thiserror::Error;
+
+pub type Result<T, E = ProgramError> = std::result::Result<T, E>;
+
+/// Ran out of bytes while decoding
+#[derive(Debug, Clone, Copy, Error)]
+#[error("ended unexpectedly")]
+pub struct UnexpectedEnd;
+
+#[derive(Debug, Clone, Error)]
+pub enum ProgramError {
+    #[error("not a sonance program")]
+    InvalidMagic,
+    #[error("program version {0} is not supported")]
+    UnsupportedVersion(u16),
+    #[error("unknown section {0}")]
+    UnknownSection(u8),
+    #[error("unknown symbol kind {0}")]
+    UnknownSymbol(u8),
+    #[error("heap size {0} is over the maximum of {}", super::MAX_HEAP_SIZE)]
+    HeapTooLarge(u32),
+    #[error("symbol name is not valid utf-8")]
+    InvalidSymbolName,
+    #[error("program ended unexpectedly")]
+    UnexpectedEnd,
+}
+
+impl From<UnexpectedEnd> for ProgramError {
+    fn from(_: UnexpectedEnd) -> Self {
+        Self::UnexpectedEnd
+    }
+}
@@ -0,0 +1,204 @@
+mod error;
+
+use std::collections::BTreeMap;
+
+pub use crate::program::error::{ProgramError, Result, UnexpectedEnd};
+
+pub const MAGIC: [u8; 4] = *b"SONC";
+pub const VERSION: u16 = 1;
+
+/// Largest heap a program can start with, so loading one can't exhaust the host's memory
+pub const MAX_HEAP_SIZE: u32 = 16 * 1024 * 1024;
+
+mod section {
+    pub const CODE: u8 = 1;
+    pub const DATA: u8 = 2;
+    pub const SYMBOLS: u8 = 3;
+    pub const HEAP: u8 = 4;
+}
+
+mod symbol {
+    pub const LABEL: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const GLOBAL: u8 = 2;
+}
+
+/// Assembled bytecode, along with everything needed to run or inspect it
+///
+/// Encoded as the magic bytes, a `u16` version, then any number of sections.
+/// Each section is an id byte and a `u32` length followed by its contents,
+/// and all integers are little endian.
+///
+/// - `1`: Code
+/// - `2`: Read-only data, loaded with `load_data`
+/// - `3`: Symbols, each a kind byte (`0` label, `1` data, `2` global), a `u16` name length, the name, and a `u32` address
+/// - `4`: Heap size, as a `u32` no larger than `MAX_HEAP_SIZE`
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    /// Address in `code` of each label
+    pub labels: BTreeMap<String, u32>,
+    /// Offset in `data` of each data entry
+    pub data_labels: BTreeMap<String, u32>,
+    /// Bytes of zeroed heap the VM starts with, for `load_global` and friends
+    pub heap_size: u32,
+    /// Address in the heap of each global
+    pub globals: BTreeMap<String, u32>,
+}
+
+impl Program {
+    pub fn new(code: Vec<u8>) -> Self {
+        Self {
+            code,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `bytes` look like an encoded program rather than source
+    pub fn is_encoded(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = MAGIC.to_vec();
+        output.extend(VERSION.to_le_bytes());
+
+        write_section(&mut output, section::CODE, &self.code);
+        write_section(&mut output, section::DATA, &self.data);
+        write_section(&mut output, section::HEAP, &self.heap_size.to_le_bytes());
+
+        let mut symbols = vec![];
+        for (kind, symbols_of_kind) in [
+            (symbol::LABEL, &self.labels),
+            (symbol::DATA, &self.data_labels),
+            (symbol::GLOBAL, &self.globals),
+        ] {
+            for (name, address) in symbols_of_kind {
+                symbols.push(kind);
+                symbols.extend((name.len() as u16).to_le_bytes());
+                symbols.extend(name.as_bytes());
+                symbols.extend(address.to_le_bytes());
+            }
+        }
+        write_section(&mut output, section::SYMBOLS, &symbols);
+
+        output
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ProgramError::InvalidMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ProgramError::UnsupportedVersion(version));
+        }
+
+        let mut program = Self::default();
+
+        while !reader.is_empty() {
+            let id = reader.u8()?;
+            let contents = reader.bytes()?;
+
+            match id {
+                section::CODE => program.code = contents.to_vec(),
+                section::DATA => program.data = contents.to_vec(),
+                section::SYMBOLS => program.read_symbols(contents)?,
+                section::HEAP => {
+                    program.heap_size = Reader(contents).u32()?;
+                    if program.heap_size > MAX_HEAP_SIZE {
+                        return Err(ProgramError::HeapTooLarge(program.heap_size));
+                    }
+                }
+                id => return Err(ProgramError::UnknownSection(id)),
+            }
+        }
+
+        Ok(program)
+    }
+
+    fn read_symbols(&mut self, contents: &[u8]) -> Result<()> {
+        let mut reader = Reader(contents);
+
+        while !reader.is_empty() {
+            let kind = reader.u8()?;
+            let len = reader.u16()?;
+            let name = std::str::from_utf8(reader.take(len as usize)?)
+                .map_err(|_| ProgramError::InvalidSymbolName)?
+                .to_owned();
+            let address = reader.u32()?;
+
+            match kind {
+                symbol::LABEL => self.labels.insert(name, address),
+                symbol::DATA => self.data_labels.insert(name, address),
+                symbol::GLOBAL => self.globals.insert(name, address),
+                kind => return Err(ProgramError::UnknownSymbol(kind)),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+fn write_section(output: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    output.push(id);
+    write_bytes(output, contents);
+}
+
+/// Little endian decoding shared by programs and snapshots
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], UnexpectedEnd> {
+        if self.0.len() < len {
+            return Err(UnexpectedEnd);
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, UnexpectedEnd> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, UnexpectedEnd> {
+        Ok(u16::from_le_bytes(
+            self.take(2)?.try_into().expect("took 2 bytes"),
+        ))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, UnexpectedEnd> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("took 4 bytes"),
+        ))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, UnexpectedEnd> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().expect("took 8 bytes"),
+        ))
+    }
+
+    /// A `u32` length followed by that many bytes
+    pub fn bytes(&mut self) -> Result<&'a [u8], UnexpectedEnd> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+}
+
+/// Write a `u32` length followed by `bytes`, to be read with `Reader::bytes`
+pub(crate) fn write_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    output.extend((bytes.len() as u32).to_le_bytes());
+    output.extend(bytes);
+}
@@ -1,17 +1,36 @@
 use pretty_assertions::assert_eq;
+use proptest::prelude::*;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use crate::{
-    device::memory::Memory,
+    debugger::{Debugger, Stop},
+    device::{
+        clock::Clock,
+        filesystem::{Filesystem, MAX_PATH_LENGTH},
+        framebuffer::{Format, Framebuffer, MAX_SIZE},
+        kind,
+        memory::{Memory, DEFAULT_MAX_LENGTH},
+        random::Random,
+        status, Device, DeviceError,
+    },
+    disassembler::disassemble,
+    instruction::Instruction,
     parser,
-    vm::{Frame, Frames, VM},
+    program::{Program, ProgramError, MAX_HEAP_SIZE},
+    vm::{Arithmetic, Frame, Frames, Snapshot, SnapshotError, Status, Trace, VMError, VM},
 };
 
 impl VM<'_> {
     /// Create a fresh VM with this VM's instructions, run it to completion, and assert that it reaches the same state as this VM
     fn run_and_asset(self) {
-        let mut vm = VM::new(self.instructions.clone());
+        let mut vm = VM {
+            instructions: self.instructions.clone(),
+            data: self.data.clone(),
+            heap: vec![0; self.heap.len()],
+            arithmetic: self.arithmetic,
+            ..Default::default()
+        };
         match vm.run() {
             Ok(()) => assert_eq!(vm, self),
             Err(error) => panic!("{error}"),
@@ -19,10 +38,14 @@ impl VM<'_> {
     }
 }
 
-fn parse(src: &str) -> Vec<u8> {
+fn program(src: &str) -> Program {
     parser::parse(src).unwrap()
 }
 
+fn parse(src: &str) -> Vec<u8> {
+    program(src).code
+}
+
 #[test]
 fn empty_program() {
     VM {
@@ -113,7 +136,7 @@ fn jump() {
         ",
         ),
 
-        instruction_index: 3,
+        instruction_index: 6,
         ..Default::default()
     }
     .run_and_asset();
@@ -135,7 +158,7 @@ fn jump_if() {
         ",
         ),
 
-        instruction_index: 11,
+        instruction_index: 17,
         ..Default::default()
     }
     .run_and_asset();
@@ -151,7 +174,7 @@ fn load_uninitialized() {
         ",
         ),
 
-        instruction_index: 3,
+        instruction_index: 4,
         stack: vec![0],
         ..Default::default()
     }
@@ -168,11 +191,8 @@ fn store() {
         ",
         ),
 
-        instruction_index: 5,
-        frames: Frames::new(vec![Frame {
-            return_index: 0,
-            variables: HashMap::from([(0, 42)]),
-        }]),
+        instruction_index: 6,
+        frames: Frames::new(vec![Frame::default()], vec![42]).unwrap(),
         ..Default::default()
     }
     .run_and_asset();
@@ -189,12 +209,9 @@ fn load_store() {
         ",
         ),
 
-        instruction_index: 8,
+        instruction_index: 10,
         stack: vec![42],
-        frames: Frames::new(vec![Frame {
-            return_index: 0,
-            variables: HashMap::from([(0, 42)]),
-        }]),
+        frames: Frames::new(vec![Frame::default()], vec![42]).unwrap(),
         ..Default::default()
     }
     .run_and_asset();
@@ -226,11 +243,8 @@ fn if_else() {
         ",
         ),
 
-        instruction_index: 35,
-        frames: Frames::new(vec![Frame {
-            return_index: 0,
-            variables: HashMap::from([(0, 6), (1, 4), (2, 6)]),
-        }]),
+        instruction_index: 49,
+        frames: Frames::new(vec![Frame::default()], vec![6, 4, 6]).unwrap(),
         ..Default::default()
     }
     .run_and_asset();
@@ -265,11 +279,8 @@ fn while_mul() {
         ",
         ),
 
-        instruction_index: 46,
-        frames: Frames::new(vec![Frame {
-            return_index: 0,
-            variables: HashMap::from([(0, 6), (1, 0), (2, 24)]),
-        }]),
+        instruction_index: 61,
+        frames: Frames::new(vec![Frame::default()], vec![6, 0, 24]).unwrap(),
         ..Default::default()
     }
     .run_and_asset()
@@ -280,13 +291,13 @@ fn call_ret_empty() {
     VM {
         instructions: parse(
             "
-            call #func
+            call #func 0
             halt
             #func
-            return
+            return 0
         ",
         ),
-        instruction_index: 3,
+        instruction_index: 8,
         ..Default::default()
     }
     .run_and_asset();
@@ -297,14 +308,15 @@ fn call_ret_const() {
     VM {
         instructions: parse(
             "
-            call #func
+            call #func 0
             halt
             #func
-            return 7
+            push 7
+            return 1
         ",
         ),
 
-        instruction_index: 3,
+        instruction_index: 8,
         stack: vec![7],
         ..Default::default()
     }
@@ -316,15 +328,18 @@ fn call_ret_double() {
     VM {
         instructions: parse(
             "
-            call #func 3
+            call #func 1 3
             halt
             #func
+            locals &n
+            load &n
             mul 2
-            return
+            return 1
+            end
         ",
         ),
 
-        instruction_index: 5,
+        instruction_index: 10,
         stack: vec![6],
         ..Default::default()
     }
@@ -336,28 +351,27 @@ fn max() {
     VM {
         instructions: parse(
             "
-            call #max 4 6
+            call #max 2 4 6
             halt
 
             #max
-            store &b
-            store 0
-
+            locals &a &b
             load &a
             load &b
             gt
             jump_if #else
 
             load &a
-            return
+            return 1
 
             #else
             load &b
-            return
+            return 1
+            end
         ",
         ),
 
-        instruction_index: 7,
+        instruction_index: 12,
         stack: vec![6],
         ..Default::default()
     }
@@ -380,6 +394,558 @@ fn large_number() {
     .run_and_asset()
 }
 
+#[test]
+fn large_program() {
+    // Pad the program well past what a u16 could address
+    let padding = "noop\n".repeat(70_000);
+
+    VM {
+        instructions: parse(&format!(
+            "
+            jump #end
+            #start
+            call #func 1 3
+            halt
+            {padding}
+            #func
+            locals &n
+            load &n
+            mul 2
+            return 1
+            end
+
+            #end
+            jump #start
+            "
+        )),
+
+        instruction_index: 16,
+        stack: vec![6],
+        ..Default::default()
+    }
+    .run_and_asset()
+}
+
+#[test]
+fn wide_arithmetic() {
+    // Inline arguments are pushed last to first, so they're the operands in reverse
+    VM {
+        instructions: parse(
+            "
+            mul_u16 300u16 200u16
+            div_u32 7u32 1_000_000u32
+            sub_u64 0u64 1u64
+            halt
+        ",
+        ),
+
+        instruction_index: 37,
+        stack: [
+            60_000u16.to_le_bytes().as_slice(),
+            &142_857u32.to_le_bytes(),
+            &1u64.to_le_bytes(),
+        ]
+        .concat(),
+        ..Default::default()
+    }
+    .run_and_asset()
+}
+
+#[test]
+fn signed_arithmetic() {
+    VM {
+        instructions: parse(
+            "
+            div_i32 4i32 -12i32
+            gt_i8 1i8 -1i8
+            gt 1 255
+            eq_u16 258u16 258u16
+            halt
+        ",
+        ),
+
+        instruction_index: 28,
+        stack: [(-3i32).to_le_bytes().as_slice(), &[0, 1, 1]].concat(),
+        ..Default::default()
+    }
+    .run_and_asset()
+}
+
+#[test]
+fn wrapping_overflow() {
+    VM {
+        instructions: parse(
+            "
+            add 1 255
+            overflow
+            overflow
+            mul_i16 2i16 -32_768i16
+            overflow
+            halt
+        ",
+        ),
+
+        instruction_index: 15,
+        stack: [&[0, 1, 0], 0i16.to_le_bytes().as_slice(), &[1]].concat(),
+        ..Default::default()
+    }
+    .run_and_asset()
+}
+
+#[test]
+fn checked_overflow() {
+    let run = |src| {
+        let mut vm = VM::new(program(src));
+        vm.arithmetic = Arithmetic::Checked;
+        vm.run()
+    };
+
+    assert!(matches!(
+        run("sub 1 0"),
+        Err(VMError::ArithmeticOverflow(Instruction::Sub, 4))
+    ));
+    assert!(matches!(
+        run("div_i8 -1i8 -128i8"),
+        Err(VMError::ArithmeticOverflow(Instruction::DivI8, 4))
+    ));
+    assert!(run("add_u16 1u16 65_534u16\nhalt").is_ok());
+}
+
+#[test]
+fn divide_by_zero() {
+    assert!(matches!(
+        VM::new(program("div_u32 0u32 1u32")).run(),
+        Err(VMError::DivideByZero(Instruction::DivU32, 10))
+    ));
+}
+
+#[test]
+fn load_data() {
+    let program = program(
+        r#"
+        data greeting "hi \"you\"" '!'
+        data number 258u16
+
+        load_data $number
+        load_data $greeting
+        halt
+        "#,
+    );
+
+    assert_eq!(program.data, b"hi \"you\"!\x02\x01");
+
+    VM {
+        instructions: program.code,
+        data: program.data,
+        instruction_index: 12,
+        stack: vec![2, b'h'],
+        ..Default::default()
+    }
+    .run_and_asset()
+}
+
+#[test]
+fn encode_decode() {
+    let program = program(
+        r#"
+        data greeting "hello"
+        #start
+        load_data $greeting
+        jump #start
+        "#,
+    );
+
+    let bytes = program.encode();
+    assert!(Program::is_encoded(&bytes));
+    assert_eq!(Program::decode(&bytes).unwrap(), program);
+    assert_eq!(program.labels["start"], 0);
+    assert_eq!(program.data_labels["greeting"], 0);
+
+    assert!(matches!(
+        Program::decode(b"halt"),
+        Err(ProgramError::InvalidMagic)
+    ));
+    assert!(matches!(
+        Program::decode(&bytes[..bytes.len() - 1]),
+        Err(ProgramError::UnexpectedEnd)
+    ));
+}
+
+#[test]
+fn disassemble_labels() {
+    let program = program(
+        r#"
+        data greeting "hi"
+        call #func 1 3
+        halt
+        #func
+        load_data $greeting
+        jump_if #func 0
+        return 1
+        "#,
+    );
+
+    assert_eq!(
+        disassemble(&program).unwrap(),
+        "data greeting 104 105\n\
+         \n\
+         push 3\n\
+         push 1\n\
+         push #func\n\
+         call\n\
+         halt\n\
+         #func\n\
+         push $greeting\n\
+         load_data\n\
+         push 0\n\
+         push #func\n\
+         jump_if\n\
+         push 1\n\
+         return\n"
+    );
+}
+
+/// Instructions that can be placed anywhere in generated code
+#[derive(Debug, Clone)]
+enum Generated {
+    Plain(Instruction),
+    Push(Instruction, u64),
+    /// A jump, jump_if or call to the start of the instruction at this index
+    Jump(Instruction, usize),
+    /// A data or heap instruction at a `u32` offset, which may or may not have a name
+    Reference(Instruction, u32),
+}
+
+fn generated() -> impl Strategy<Value = Generated> {
+    let plain: Vec<_> = (0..=u8::MAX)
+        .filter_map(|code| Instruction::try_from(code).ok())
+        .filter(|instruction| !instruction.name().starts_with("push"))
+        .collect();
+    let plain = prop::sample::select(plain);
+    let push = (
+        prop_oneof![
+            Just(Instruction::Push),
+            Just(Instruction::PushU16),
+            Just(Instruction::PushU32),
+            Just(Instruction::PushU64),
+        ],
+        any::<u64>(),
+    );
+    let jump = (
+        prop_oneof![
+            Just(Instruction::Jump),
+            Just(Instruction::JumpIf),
+            Just(Instruction::Call),
+        ],
+        any::<usize>(),
+    );
+    let reference = (
+        prop_oneof![
+            Just(Instruction::LoadData),
+            Just(Instruction::LoadGlobal),
+            Just(Instruction::StoreGlobal),
+            Just(Instruction::LoadIndirect),
+            Just(Instruction::StoreIndirect),
+        ],
+        0..32u32,
+    );
+
+    prop_oneof![
+        plain.prop_map(Generated::Plain),
+        push.prop_map(|(instruction, value)| Generated::Push(instruction, value)),
+        jump.prop_map(|(instruction, index)| Generated::Jump(instruction, index)),
+        reference.prop_map(|(instruction, offset)| Generated::Reference(instruction, offset)),
+    ]
+}
+
+/// Names for labels, data and globals, including ones the disassembler would make up itself
+fn name() -> impl Strategy<Value = String> {
+    prop_oneof!["(label|data|global)_[0-9]", "[a-z]{1,4}"]
+}
+
+/// Assemble generated instructions straight to bytes, without going through the parser
+fn assemble(generated: &[Generated]) -> Vec<u8> {
+    let len = |generated: &Generated| match generated {
+        Generated::Plain(_) => 1,
+        Generated::Push(Instruction::Push, _) => 2,
+        Generated::Push(Instruction::PushU16, _) => 3,
+        Generated::Push(Instruction::PushU32, _) => 5,
+        Generated::Push(_, _) => 9,
+        Generated::Jump(_, _) | Generated::Reference(_, _) => 6,
+    };
+
+    let addresses: Vec<u32> = generated
+        .iter()
+        .scan(0, |address, generated| {
+            let start = *address;
+            *address += len(generated);
+            Some(start)
+        })
+        .collect();
+
+    let mut code = vec![];
+    for generated in generated {
+        match generated {
+            Generated::Plain(instruction) => code.push(*instruction as u8),
+            Generated::Push(instruction, value) => {
+                let len = len(generated) as usize - 1;
+                code.push(*instruction as u8);
+                code.extend(&value.to_le_bytes()[..len]);
+            }
+            Generated::Jump(instruction, index) => {
+                code.push(Instruction::PushU32 as u8);
+                code.extend(addresses[index % addresses.len()].to_le_bytes());
+                code.push(*instruction as u8);
+            }
+            Generated::Reference(instruction, offset) => {
+                code.push(Instruction::PushU32 as u8);
+                code.extend(offset.to_le_bytes());
+                code.push(*instruction as u8);
+            }
+        }
+    }
+
+    code
+}
+
+proptest! {
+    #[test]
+    fn disassemble_round_trip(
+        generated in prop::collection::vec(generated(), 0..64),
+        labels in prop::collection::btree_map(name(), 0..256u32, 0..4),
+        data in prop::collection::vec(any::<u8>(), 0..16),
+        data_labels in prop::collection::btree_map(name(), 0..20u32, 0..4),
+        heap_size in 0..32u32,
+        globals in prop::collection::btree_map(name(), 0..32u32, 0..4),
+    ) {
+        let program = Program {
+            code: assemble(&generated),
+            data,
+            labels,
+            data_labels,
+            heap_size,
+            globals,
+        };
+        let src = disassemble(&program).unwrap();
+
+        let reassembled = parser::parse(&src).unwrap();
+        prop_assert_eq!(reassembled.code, program.code);
+        prop_assert_eq!(reassembled.data, program.data);
+        prop_assert_eq!(reassembled.heap_size, program.heap_size);
+    }
+}
+
+#[test]
+fn debugger() {
+    let program = program(
+        "
+        call #double 1 3
+        call #double 1
+        halt
+
+        #double
+        locals &n
+        load &n
+        mul 2
+        return 1
+        end
+        ",
+    );
+
+    let mut debugger = Debugger::new(VM::new(program.clone()), &program);
+    let mut output = vec![];
+    let mut command = |debugger: &mut Debugger, line| {
+        output.clear();
+        assert!(debugger.command(line, &mut output).unwrap());
+        String::from_utf8(output.clone()).unwrap()
+    };
+
+    assert_eq!(
+        command(&mut debugger, "break #double"),
+        "breakpoint at 19\n"
+    );
+    assert_eq!(
+        command(&mut debugger, "continue"),
+        "hit breakpoint at 19\n    19  #double push_u16 1\n"
+    );
+    assert_eq!(debugger.vm.frames.depth(), 2);
+
+    command(&mut debugger, "finish");
+    assert_eq!(debugger.vm.instruction_index, 10);
+    assert_eq!(debugger.vm.stack, [6]);
+
+    command(&mut debugger, "delete double");
+    for _ in 0..3 {
+        assert!(matches!(debugger.step_over(), Stop::Stepped));
+    }
+    assert_eq!(debugger.vm.instruction_index, 18);
+    assert_eq!(debugger.vm.stack, [12]);
+
+    assert!(command(&mut debugger, "list").contains(">     18  halt\n"));
+    assert_eq!(command(&mut debugger, "step"), "halted\n");
+    assert_eq!(command(&mut debugger, "wat"), "unknown command wat\n");
+    assert_eq!(command(&mut debugger, "device 0 2"), "no device has ID 0\n");
+
+    // Peeking at the random device mustn't move it on to the next number
+    debugger.vm.add_device(Random::new(258));
+    let before = debugger.vm.snapshot();
+    assert_eq!(
+        command(&mut debugger, "device 0 7"),
+        "[0, -, -, -, 2, 1, 0]\n"
+    );
+    assert_eq!(debugger.vm.snapshot(), before);
+    assert_eq!(
+        command(&mut debugger, "device 0 1000"),
+        "can only show 256 bytes at once\n"
+    );
+}
+
+#[test]
+fn trace() {
+    let program = program(
+        "
+        store &i 3
+        #loop
+        load &i
+        sub 1
+        dupe
+        store &i
+        jump_if #end
+        jump #loop
+        #end
+        halt
+        ",
+    );
+
+    let mut log = vec![];
+    let mut vm = VM::new(program.clone());
+    vm.trace = Some(Trace::log(&mut log));
+    vm.run().unwrap();
+    let trace = vm.trace.as_mut().unwrap();
+    trace.flush().unwrap();
+
+    assert_eq!(trace.instructions()[0], (Instruction::PushU16, 7));
+    assert_eq!(
+        trace.labels(&program.labels),
+        [
+            ("loop".to_owned(), 31),
+            ("".to_owned(), 3),
+            ("end".to_owned(), 1)
+        ]
+    );
+    assert_eq!(trace.addresses()[0], (6, Instruction::PushU16, 3));
+
+    drop(vm);
+    let log = String::from_utf8(log).unwrap();
+    assert!(log.starts_with("     0      0  push\n     2      1  push_u16\n     5      3  store\n"));
+    assert_eq!(log.lines().count(), 35);
+}
+
+#[test]
+fn budget() {
+    let mut forever = VM::new(program(
+        "
+        #loop
+        jump #loop
+        ",
+    ));
+    let mut counter = VM::new(program(
+        "
+        push 0
+        #loop
+        add 1
+        dupe
+        eq 10
+        jump_if #loop
+        halt
+        ",
+    ));
+
+    // Time-slice both, like a host running several guests would
+    let mut slices = 0;
+    while counter.run_with_budget(7).unwrap() == Status::Paused {
+        assert_eq!(forever.run_with_budget(7).unwrap(), Status::Paused);
+        slices += 1;
+    }
+
+    assert_eq!(counter.stack, [10]);
+    assert_eq!(slices, 10);
+    assert_eq!(forever.instruction_index, 0);
+    assert!(counter.halted());
+    assert_eq!(counter.run_with_budget(0).unwrap(), Status::Halted);
+    assert_eq!(counter.run_with_budget(1).unwrap(), Status::Halted);
+    assert!(!forever.halted());
+    assert_eq!(forever.run_with_budget(0).unwrap(), Status::Paused);
+}
+
+#[test]
+fn snapshot_restore() {
+    let mut vm = VM::new(program(
+        "
+        write 8u32 4 // set length
+        write 1u32 5 // resize
+        write 32u32 7
+        call #bump 0
+        write 33u32 8
+        halt
+
+        #bump
+        store &x 5
+        read 32u32
+        add 1
+        write 32u32
+        return 0
+        ",
+    ));
+    vm.add_device(Memory::empty_io());
+
+    assert_eq!(vm.run_with_budget(15).unwrap(), Status::Paused);
+    let paused = vm.snapshot();
+    assert_eq!(paused.frames.depth(), 2);
+    assert_eq!(Snapshot::decode(&paused.encode()).unwrap(), paused);
+
+    vm.run().unwrap();
+    let finished = vm.snapshot();
+    assert_eq!(
+        finished.devices,
+        BTreeMap::from([(
+            0,
+            vec![0, 4, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 8, 0, 0]
+        )])
+    );
+
+    // Rewinding and running again should end up in exactly the same place
+    vm.restore(&paused).unwrap();
+    assert_eq!(vm.snapshot(), paused);
+    vm.run().unwrap();
+    assert_eq!(vm.snapshot(), finished);
+
+    assert!(matches!(
+        VM::default().restore(&paused),
+        Err(SnapshotError::DeviceCount(0, 1))
+    ));
+    assert!(matches!(
+        Snapshot::decode(&paused.encode()[..20]),
+        Err(SnapshotError::UnexpectedEnd)
+    ));
+
+    // A snapshot that fails partway through shouldn't leave anything half rewound
+    let mut vm = VM::new(program("halt"));
+    vm.add_device(Memory::empty_io());
+    vm.add_device(Framebuffer::new(1, 1));
+    let before = vm.snapshot();
+
+    let mut broken = before.clone();
+    broken.stack = vec![1];
+    broken.devices.insert(0, paused.devices[&0].clone());
+    broken.devices.get_mut(&1).unwrap().pop();
+    assert!(matches!(
+        vm.restore(&broken),
+        Err(SnapshotError::InvalidDeviceState)
+    ));
+    assert_eq!(vm.snapshot(), before);
+}
+
 #[test]
 fn hello_world() {
     const SRC: &str = include_str!("../dev/hello_world.a");
@@ -389,10 +955,525 @@ fn hello_world() {
     let mut memory = Memory::empty_io();
     memory.add_output(&mut output);
 
-    let mut vm = VM::new(parse(SRC));
-    vm.add_device(&mut memory);
+    let mut vm = VM::new(program(SRC));
+    vm.add_device(memory);
     vm.run().unwrap();
 
-    drop(memory);
+    drop(vm);
     assert_eq!(output, b"Hello world!\n");
 }
+
+#[test]
+fn devices() {
+    let src = "
+        query_device 0
+        query_device 3
+        write 0u32 3 // select device 3
+        read 1u32
+        halt
+    ";
+
+    let mut vm = VM::new(program(src));
+    assert_eq!(vm.add_device(Memory::empty_io()), 0);
+    assert!(matches!(
+        vm.run(),
+        Err(VMError::NoSuchDevice(Instruction::Read, 19, 3))
+    ));
+    assert_eq!(vm.stack, [kind::MEMORY, kind::NONE]);
+
+    let mut vm = VM::new(program(src));
+    assert!(vm.devices.insert(3, Memory::empty_io()).is_none());
+    assert_eq!(vm.add_device(Memory::empty_io()), 0);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, [kind::MEMORY, kind::MEMORY, 0]);
+}
+
+#[test]
+fn memory() {
+    let run = |src: &str, input: &'static [u8]| {
+        let mut memory = Memory::empty_io();
+        memory.add_input(input);
+
+        let mut vm = VM::new(program(src));
+        vm.add_device(memory);
+        let result = vm.run();
+        (result, vm.stack)
+    };
+
+    // Registers wider than a byte allow more than 255 bytes of memory
+    let (result, stack) = run(
+        "
+        write 9u32 1  // set length to 256
+        write 1u32 5  // resize
+        write 287u32 7
+        read 287u32
+        read 4u32     // resized to 256
+        read 5u32
+        halt
+        ",
+        b"",
+    );
+    result.unwrap();
+    assert_eq!(stack, [7, 0, 1]);
+
+    // IO errors are reported in the command register
+    let (result, stack) = run(
+        "
+        write 8u32 4
+        write 1u32 5
+        write 16u32 4
+        write 1u32 1  // read all from input
+        read 1u32
+        write 21u32 1
+        write 1u32 2  // write to a missing output
+        read 1u32
+        halt
+        ",
+        b"ab",
+    );
+    result.unwrap();
+    assert_eq!(stack, [status::END_OF_FILE, status::NO_STREAM]);
+
+    // Invalid accesses fault the VM
+    for (src, expected) in [
+        ("read 32u32", DeviceError::OutOfBounds(32)),
+        ("read 2u32", DeviceError::OutOfBounds(2)),
+        ("write 4u32 1", DeviceError::ReadOnly(4)),
+        ("write 1u32 9", DeviceError::InvalidCommand(9)),
+        (
+            "write 16u32 1\nwrite 1u32 3",
+            DeviceError::InvalidSlice(0, 1),
+        ),
+        (
+            "write 11u32 255\nwrite 1u32 5",
+            DeviceError::TooLong(0xff00_0000, DEFAULT_MAX_LENGTH),
+        ),
+    ] {
+        let (result, _) = run(src, b"");
+        match result {
+            Err(VMError::Device(Instruction::Read | Instruction::Write, _, 0, error)) => {
+                assert_eq!(error, expected)
+            }
+            result => panic!("{src} returned {result:?}"),
+        }
+    }
+}
+
+#[test]
+fn clock() {
+    let mut clock = Clock::new();
+    let latch = |clock: &mut Clock| {
+        clock.write(1, 0).unwrap();
+        let elapsed: Vec<_> = (4..12).map(|index| clock.read(index).unwrap()).collect();
+        u64::from_le_bytes(elapsed.try_into().unwrap())
+    };
+
+    let first = latch(&mut clock);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let second = latch(&mut clock);
+    assert!(second >= first + 2000);
+
+    assert_eq!(clock.read(2).unwrap(), 0);
+    clock.write(1, 2).unwrap(); // start a timer with no duration
+    assert_eq!(clock.read(2).unwrap(), 1);
+
+    assert_eq!(clock.write(4, 0), Err(DeviceError::ReadOnly(4)));
+    assert_eq!(clock.write(1, 3), Err(DeviceError::InvalidCommand(3)));
+}
+
+#[test]
+fn random() {
+    let bytes =
+        |random: &mut Random| -> Vec<u8> { (0..16).map(|_| random.read(1).unwrap()).collect() };
+
+    let mut random = Random::new(42);
+    let first = bytes(&mut random);
+    assert_eq!(bytes(&mut Random::new(42)), first);
+    assert_ne!(bytes(&mut Random::new(43)), first);
+    assert_ne!(bytes(&mut random), first);
+
+    // Reseeding through the registers starts the same sequence again
+    for (index, byte) in (4..12).zip(42u64.to_le_bytes()) {
+        random.write(index, byte).unwrap();
+    }
+    random.write(1, 0).unwrap();
+    assert_eq!(bytes(&mut random), first);
+}
+
+#[test]
+fn filesystem() {
+    let root = std::env::temp_dir().join(format!("sonance-filesystem-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+
+    let mut filesystem = Filesystem::new(&root).unwrap();
+    let command = |filesystem: &mut Filesystem, command, path: &str| {
+        for byte in path.bytes() {
+            filesystem.write(8, byte).unwrap();
+        }
+        filesystem.write(1, command).unwrap();
+        filesystem.read(1).unwrap()
+    };
+
+    assert_eq!(command(&mut filesystem, 1, "hi.txt"), status::OK);
+    filesystem.write(9, b'h').unwrap();
+    filesystem.write(9, b'i').unwrap();
+    assert_eq!(command(&mut filesystem, 3, ""), status::OK);
+    assert_eq!(std::fs::read(root.join("hi.txt")).unwrap(), b"hi");
+
+    assert_eq!(command(&mut filesystem, 5, "hi.txt"), status::OK);
+    assert_eq!(filesystem.read(4).unwrap(), 2);
+
+    assert_eq!(command(&mut filesystem, 0, "hi.txt"), status::OK);
+    assert_eq!(filesystem.read(9).unwrap(), b'h');
+    assert_eq!(filesystem.read(9).unwrap(), b'i');
+    assert_eq!(filesystem.read(9).unwrap(), 0);
+    assert_eq!(filesystem.read(1).unwrap(), status::END_OF_FILE);
+
+    assert_eq!(command(&mut filesystem, 3, ""), status::OK);
+    assert_eq!(command(&mut filesystem, 4, "hi.txt"), status::OK);
+    assert_eq!(command(&mut filesystem, 0, "hi.txt"), status::IO_ERROR);
+    assert_eq!(command(&mut filesystem, 3, ""), status::NO_STREAM);
+
+    for path in ["../escape.txt", "/etc/passwd", ""] {
+        assert_eq!(command(&mut filesystem, 1, path), status::INVALID_PATH);
+    }
+    let long = "a".repeat(MAX_PATH_LENGTH + 100);
+    assert_eq!(command(&mut filesystem, 1, &long), status::INVALID_PATH);
+    assert_eq!(
+        command(&mut filesystem, 1, &long[..MAX_PATH_LENGTH + 1]),
+        status::INVALID_PATH
+    );
+    assert_eq!(filesystem.read(8), Err(DeviceError::WriteOnly(8)));
+
+    // Even a dangling symlink would be followed out of the root
+    #[cfg(unix)]
+    {
+        let outside = root.with_extension("outside");
+        std::os::unix::fs::symlink(&outside, root.join("link.txt")).unwrap();
+        for create in [1, 2] {
+            assert_eq!(
+                command(&mut filesystem, create, "link.txt"),
+                status::INVALID_PATH
+            );
+        }
+        assert!(!outside.exists());
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn framebuffer() {
+    let mut output = vec![];
+
+    let mut framebuffer = Framebuffer::new(0, 0);
+    framebuffer.set_output(&mut output);
+
+    let mut vm = VM::new(program(
+        "
+        write 4u32 2    // width
+        write 8u32 1    // height
+        write 12u32 255 // red
+        write 1u32 2    // resize, clearing to red
+        write 35u32 0   // second pixel to blue
+        write 37u32 255
+        write 1u32 0    // present
+        read 1u32
+        halt
+        ",
+    ));
+    vm.add_device(framebuffer);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, [status::OK]);
+
+    let snapshot = vm.snapshot();
+    vm.restore(&snapshot).unwrap();
+    assert!(matches!(
+        vm.devices.get_mut(0).unwrap().write(38, 0),
+        Err(DeviceError::OutOfBounds(38))
+    ));
+
+    drop(vm);
+    assert_eq!(output, b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff");
+
+    let mut framebuffer = Framebuffer::new(0, 0);
+    framebuffer.write(5, (MAX_SIZE >> 8) as u8).unwrap();
+    framebuffer.write(4, 1).unwrap();
+    assert_eq!(
+        framebuffer.write(1, 2),
+        Err(DeviceError::InvalidSize(MAX_SIZE + 1, 0))
+    );
+}
+
+#[test]
+fn framebuffer_png() {
+    let mut output = vec![];
+
+    let mut framebuffer = Framebuffer::new(2, 1);
+    framebuffer.set_output(&mut output);
+    framebuffer.set_format(Format::Png);
+    for (index, value) in [(32, 255), (37, 255)] {
+        framebuffer.write(index, value).unwrap();
+    }
+    framebuffer.write(1, 0).unwrap();
+    assert_eq!(framebuffer.read(1), Ok(status::OK));
+
+    framebuffer.write(4, 0).unwrap();
+    framebuffer.write(1, 2).unwrap();
+    assert_eq!(framebuffer.write(1, 0), Err(DeviceError::InvalidSize(0, 1)));
+
+    drop(framebuffer);
+    assert_eq!(
+        output,
+        [
+            b"\x89PNG\r\n\x1a\n".as_slice(),
+            b"\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x01\x08\x02\0\0\0\x7b\x40\xe8\xdd",
+            b"\0\0\0\x12IDAT\x08\x1d\x01\x07\0\xf8\xff\0\xff\0\0\0\0\xff\x07\0\x01\xff\x1e\x17\xdf\xa3",
+            b"\0\0\0\0IEND\xae\x42\x60\x82",
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn globals() {
+    let src = "
+        global counter
+        global point 2 // x and y
+        global array 4
+
+        call #bump 0
+        call #bump 0
+
+        // point.y = counter * 10
+        load_global @counter
+        mul 10
+        store_indirect @point 1u32
+
+        // array[i] = i for i in 0..4
+        store &i 0
+        #fill
+        load &i
+        dupe
+        push 0u16 // widen i to a u32 offset
+        push 0
+        store_indirect @array
+        load &i
+        add 1
+        dupe
+        store &i
+        eq 4
+        jump_if #fill
+
+        load_indirect @array 3u32
+        halt
+
+        #bump
+        load_global @counter
+        add 1
+        store_global @counter
+        return 0
+    ";
+
+    let program = program(src);
+    assert_eq!(program.heap_size, 7);
+    assert_eq!(
+        program.globals,
+        BTreeMap::from([
+            ("counter".to_owned(), 0),
+            ("point".to_owned(), 1),
+            ("array".to_owned(), 3),
+        ])
+    );
+
+    let mut vm = VM::new(program.clone());
+    vm.run().unwrap();
+    assert_eq!(vm.heap, [2, 0, 20, 0, 1, 2, 3]);
+    assert_eq!(vm.stack, [3]);
+
+    let snapshot = vm.snapshot();
+    assert_eq!(Snapshot::decode(&snapshot.encode()).unwrap(), snapshot);
+    assert_eq!(Program::decode(&program.encode()).unwrap(), program);
+
+    let disassembled = parser::parse(&disassemble(&program).unwrap()).unwrap();
+    assert_eq!(disassembled.code, program.code);
+    assert_eq!(disassembled.heap_size, program.heap_size);
+
+    assert!(matches!(
+        VM::new(self::program("load_global 7u32")).run(),
+        Err(VMError::HeapIndexOutOfBounds(7, 5))
+    ));
+    assert!(matches!(
+        parser::parse("load_global @missing"),
+        Err(parser::ParseError::GlobalNotFound(name)) if name == "missing"
+    ));
+    assert!(matches!(
+        parser::parse("global counter\nglobal counter 4"),
+        Err(parser::ParseError::DuplicateGlobal(name)) if name == "counter"
+    ));
+    assert!(matches!(
+        parser::parse(&format!("global huge {}", MAX_HEAP_SIZE + 1)),
+        Err(parser::ParseError::HeapTooLarge)
+    ));
+
+    let huge = Program {
+        heap_size: MAX_HEAP_SIZE + 1,
+        ..program
+    };
+    assert!(matches!(
+        Program::decode(&huge.encode()),
+        Err(ProgramError::HeapTooLarge(size)) if size == MAX_HEAP_SIZE + 1
+    ));
+}
+
+#[test]
+fn locals() {
+    let program = program(
+        "
+        store &x 7
+        call #double 1 3
+        load &x
+        halt
+
+        #double
+        locals &n // reserve a slot for the argument n, and one for result
+        load &n
+        mul 2
+        store &result
+        load &result
+        return 1
+        end
+        ",
+    );
+    assert_eq!(
+        program.code[21..25],
+        [Instruction::PushU16 as u8, 2, 0, Instruction::Reserve as u8]
+    );
+
+    let mut vm = VM::new(program);
+    assert_eq!(vm.run_with_budget(9).unwrap(), Status::Paused);
+    let frames: Vec<_> = vm.frames.iter().collect();
+    assert_eq!(
+        frames,
+        [
+            (&Frame::default(), &[7][..]),
+            (
+                &Frame {
+                    base: 1,
+                    stack_base: 0,
+                    return_index: 16,
+                    arity: 1,
+                    reserved: true,
+                },
+                &[3, 0][..]
+            ),
+        ]
+    );
+
+    vm.run().unwrap();
+    assert_eq!(vm.stack, [6, 7]);
+    assert_eq!(vm.frames.slots(), [7]);
+
+    for access in ["push 1\npush 1u16\nstore", "push 1u16\nload"] {
+        let mut vm = VM::new(self::program(&format!(
+            "call #reserved 0\nhalt\n#reserved\nlocals &x\n{access}\nend"
+        )));
+        assert!(matches!(vm.run(), Err(VMError::SlotOutOfBounds(_, _, 1))));
+    }
+
+    // Top level variables are back in scope after the function ends, and aren't reserved by it
+    let program = self::program(
+        "
+        store &x 9
+        store &y 4
+        call #f 0
+        jump #after
+
+        #f
+        locals
+        return 0
+        end
+
+        #after
+        load &y
+        halt
+        ",
+    );
+    assert_eq!(
+        program.code[26..30],
+        [Instruction::PushU16 as u8, 0, 0, Instruction::Reserve as u8]
+    );
+    let mut vm = VM::new(program);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, [4]);
+
+    assert!(matches!(
+        parser::parse("#f\nlocals\nreturn 0"),
+        Err(parser::ParseError::MissingEnd)
+    ));
+    assert!(matches!(
+        parser::parse("#f\nlocals\n#g\nlocals\nend"),
+        Err(parser::ParseError::MissingEnd)
+    ));
+    assert!(matches!(
+        parser::parse("end"),
+        Err(parser::ParseError::UnmatchedEnd)
+    ));
+
+    let frame = |base| Frame {
+        base,
+        ..Default::default()
+    };
+    assert!(Frames::new(vec![frame(0), frame(2)], vec![0; 2]).is_some());
+    assert!(Frames::new(vec![frame(2), frame(1)], vec![0; 2]).is_none());
+    assert!(Frames::new(vec![frame(0), frame(3)], vec![0; 2]).is_none());
+}
+
+#[test]
+fn calling_convention() {
+    let mut vm = VM::new(program(
+        "
+        push 9
+        call #divmod 2 17 5
+        halt
+
+        #divmod
+        locals &a &b
+        push 42 // left behind, and dropped on return
+        load &a
+        load &b
+        div
+        load &a
+        load &b
+        load &a
+        load &b
+        div
+        mul
+        sub
+        return 2
+        end
+        ",
+    ));
+    vm.run().unwrap();
+    assert_eq!(vm.stack, [9, 3, 2]);
+
+    for (src, expected) in [
+        ("call #f 2 1\n#f\nreturn 0", (Instruction::Call, 2, 1)),
+        ("call #f 1 1\n#f\nreturn 1", (Instruction::Return, 1, 0)),
+        (
+            "call #f 2 1 1\n#f\nlocals &a\nreturn 0\nend",
+            (Instruction::Reserve, 2, 1),
+        ),
+    ] {
+        match VM::new(program(src)).run() {
+            Err(VMError::ArityMismatch(instruction, _, wanted, found)) => {
+                assert_eq!((instruction, wanted, found), expected)
+            }
+            result => panic!("{src} returned {result:?}"),
+        }
+    }
+
+    let mut vm = VM::new(program("push 5\nreturn 0"));
+    assert!(matches!(vm.run(), Err(VMError::TopLevelReturn(_))));
+    assert_eq!(vm.frames.depth(), 1);
+}
@@ -1,19 +1,63 @@
 use thiserror::Error;
 
-use crate::vm::Instruction;
+use crate::{device::DeviceError, program::UnexpectedEnd, vm::Instruction};
 
 pub type Result<T, E = VMError> = std::result::Result<T, E>;
 
 #[derive(Debug, Clone, Error)]
 pub enum VMError {
     #[error("instruction {0} at index {1} wanted a value from the stack, but it was empty")]
-    EmptyStack(Instruction, u8),
+    EmptyStack(Instruction, u32),
     #[error("attempted to return at index {0} outside of function call")]
-    TopLevelReturn(u8),
+    TopLevelReturn(u32),
+    #[error("instruction {0} at index {1} expected {2} values, but found {3}")]
+    ArityMismatch(Instruction, u32, u8, u32),
     #[error("instruction {0} at index {1} attempted to access frame, but none exist")]
-    ExpectedFrame(Instruction, u8),
+    ExpectedFrame(Instruction, u32),
+    #[error("instruction {0} at index {1} accessed slot {2}, past the end of the reserved frame")]
+    SlotOutOfBounds(Instruction, u32, u16),
     #[error("attempted to access instruction index {0}, but it was out of bounds")]
-    InstructionIndexOutOfBounds(u8),
+    InstructionIndexOutOfBounds(u32),
+    #[error("attempted to load data at offset {0} from index {1}, but it was out of bounds")]
+    DataIndexOutOfBounds(u32, u32),
+    #[error("attempted to access heap address {0} from index {1}, but it was out of bounds")]
+    HeapIndexOutOfBounds(u32, u32),
     #[error("{0} at index {1} is not a valid instruction")]
-    InvalidInstruction(u8, u8),
+    InvalidInstruction(u8, u32),
+    #[error("instruction {0} at index {1} accessed device {2}, but none has that ID")]
+    NoSuchDevice(Instruction, u32, u8),
+    #[error("instruction {0} at index {1} faulted device {2}: {3}")]
+    Device(Instruction, u32, u8, #[source] DeviceError),
+    #[error("instruction {0} at index {1} overflowed")]
+    ArithmeticOverflow(Instruction, u32),
+    #[error("instruction {0} at index {1} attempted to divide by zero")]
+    DivideByZero(Instruction, u32),
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum SnapshotError {
+    #[error("not a sonance snapshot")]
+    InvalidMagic,
+    #[error("snapshot version {0} is not supported")]
+    UnsupportedVersion(u16),
+    #[error("snapshot ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("{0} is not a valid instruction")]
+    InvalidInstruction(u8),
+    #[error("frame bases are out of order or past the end of the slots")]
+    InvalidFrames,
+    #[error("{0} is not a valid arithmetic mode")]
+    InvalidArithmetic(u8),
+    #[error("the VM has {0} devices, but the snapshot has {1}")]
+    DeviceCount(usize, usize),
+    #[error("the snapshot has device {0}, but the VM doesn't")]
+    NoSuchDevice(u8),
+    #[error("device state is invalid")]
+    InvalidDeviceState,
+}
+
+impl From<UnexpectedEnd> for SnapshotError {
+    fn from(_: UnexpectedEnd) -> Self {
+        Self::UnexpectedEnd
+    }
 }
@@ -1,51 +1,143 @@
-use std::collections::HashMap;
-
-#[derive(Debug, Clone, Default, PartialEq)]
+/// A call frame, whose local slots start at `base` in `Frames`' slot stack
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Frame {
-    pub variables: HashMap<u8, u8>,
-    pub return_index: u8,
+    /// Index of this frame's first slot
+    pub base: u32,
+    /// Length of the VM's stack when this frame was called, after its arguments were taken
+    pub stack_base: u32,
+    /// Where to continue from once this frame returns
+    pub return_index: u32,
+    /// Number of arguments this frame was called with, which start off its slots
+    pub arity: u8,
+    /// Whether `reserve` has fixed how many slots this frame has
+    pub reserved: bool,
 }
 
+/// Every frame's local slots, laid out one after another on a single stack
+///
+/// Only the current frame can grow, since it's always the last one, so a slot is a
+/// single index into `slots` rather than a lookup. Once a frame is sized by `reserve`,
+/// loading or storing past its end fails. Until then, as at the top level, loading past
+/// the end gives zero and storing grows the frame to fit.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Frames {
     frames: Vec<Frame>,
+    slots: Vec<u8>,
 }
 
 impl Default for Frames {
     fn default() -> Self {
         Self {
             frames: vec![Default::default()],
+            slots: vec![],
         }
     }
 }
 
 impl Frames {
-    pub fn new(frames: Vec<Frame>) -> Self {
-        Self { frames }
+    /// `frames` in call order, with bases that index into `slots`
+    ///
+    /// `None` if the bases are out of order or past the end of `slots`.
+    pub fn new(frames: Vec<Frame>, slots: Vec<u8>) -> Option<Self> {
+        if frames
+            .iter()
+            .zip(frames.iter().skip(1))
+            .any(|(frame, next)| frame.base > next.base)
+            || frames
+                .last()
+                .is_some_and(|frame| frame.base as usize > slots.len())
+        {
+            return None;
+        }
+
+        Some(Self { frames, slots })
+    }
+
+    /// Number of frames, including the top level one
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Every frame along with its slots, from the top level one to the current call
+    pub fn iter(&self) -> impl Iterator<Item = (&Frame, &[u8])> {
+        let ends = self
+            .frames
+            .iter()
+            .skip(1)
+            .map(|frame| frame.base as usize)
+            .chain([self.slots.len()]);
+
+        self.frames
+            .iter()
+            .zip(ends)
+            .map(|(frame, end)| (frame, &self.slots[frame.base as usize..end]))
+    }
+
+    /// The whole slot stack, for every frame
+    pub fn slots(&self) -> &[u8] {
+        &self.slots
+    }
+
+    /// The frame locals are currently loaded from and stored to
+    pub fn current(&self) -> Option<&Frame> {
+        self.frames.last()
     }
 
-    pub fn call(&mut self, return_index: u8) {
+    /// Push a frame whose first slots are `args`
+    pub fn call(&mut self, return_index: u32, stack_base: u32, args: &[u8]) {
         self.frames.push(Frame {
+            base: self.slots.len() as u32,
+            stack_base,
             return_index,
-            ..Default::default()
+            arity: args.len() as u8,
+            reserved: false,
         });
+        self.slots.extend(args);
     }
 
-    pub fn ret(&mut self) -> Option<u8> {
+    /// Pop the current frame, or `None` at the top level since there's nothing to return to
+    pub fn ret(&mut self) -> Option<Frame> {
+        if self.frames.len() <= 1 {
+            return None;
+        }
+
         let frame = self.frames.pop()?;
-        Some(frame.return_index)
+        self.slots.truncate(frame.base as usize);
+        Some(frame)
     }
 
-    pub fn load(&self, variable: u8) -> Option<u8> {
+    /// Resize the current frame to `count` slots, zeroing any new ones
+    pub fn reserve(&mut self, count: u16) -> Option<()> {
+        let frame = self.frames.last_mut()?;
+        frame.reserved = true;
+
+        self.slots.resize(frame.base as usize + count as usize, 0);
+        Some(())
+    }
+
+    /// `None` if there's no current frame, or it's reserved and `slot` is past its end
+    pub fn load(&self, slot: u16) -> Option<u8> {
         let frame = self.frames.last()?;
 
-        Some(frame.variables.get(&variable).copied().unwrap_or(0))
+        match self.slots.get(frame.base as usize + slot as usize) {
+            Some(value) => Some(*value),
+            None if frame.reserved => None,
+            None => Some(0),
+        }
     }
 
-    pub fn store(&mut self, variable: u8, value: u8) -> Option<()> {
-        let frame = self.frames.last_mut()?;
+    /// `None` if there's no current frame, or it's reserved and `slot` is past its end
+    pub fn store(&mut self, slot: u16, value: u8) -> Option<()> {
+        let frame = self.frames.last()?;
+        let index = frame.base as usize + slot as usize;
 
-        frame.variables.insert(variable, value);
+        if index >= self.slots.len() {
+            if frame.reserved {
+                return None;
+            }
+            self.slots.resize(index + 1, 0);
+        }
+        self.slots[index] = value;
         Some(())
     }
 }
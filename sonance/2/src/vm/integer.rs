@@ -0,0 +1,37 @@
+/// An integer that can be stored on the stack as little endian bytes
+pub trait Integer: Copy + PartialEq + PartialOrd {
+    /// Number of bytes this integer takes up on the stack
+    const SIZE: usize;
+    const ZERO: Self;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn push_le(self, stack: &mut Vec<u8>);
+
+    /// Divide, returning whether it overflowed, which only happens for `MIN / -1`. Panics if `rhs` is zero
+    fn overflowing_div(self, rhs: Self) -> (Self, bool);
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl Integer for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+                const ZERO: Self = 0;
+
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    Self::from_le_bytes(bytes.try_into().expect("slice should be SIZE bytes long"))
+                }
+
+                fn push_le(self, stack: &mut Vec<u8>) {
+                    stack.extend(self.to_le_bytes());
+                }
+
+                fn overflowing_div(self, rhs: Self) -> (Self, bool) {
+                    <$ty>::overflowing_div(self, rhs)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8, u16, u32, u64, i8, i16, i32, i64);
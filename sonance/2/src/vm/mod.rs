@@ -1,49 +1,91 @@
 mod error;
 mod frames;
+mod integer;
+mod snapshot;
 mod step;
+mod trace;
 
 pub use crate::vm::{
-    error::{Result, VMError},
+    error::{Result, SnapshotError, VMError},
     frames::{Frame, Frames},
+    integer::Integer,
+    snapshot::Snapshot,
+    trace::{Report, Trace, TraceEvent},
 };
 use crate::{
-    device::{Device, DeviceManager},
+    device::{Device, DeviceError, DeviceManager},
     instruction::Instruction,
+    program::Program,
 };
 
+/// What happens when an arithmetic instruction overflows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Arithmetic {
+    /// Wrap around and set `VM::overflow`
+    #[default]
+    Wrapping,
+    /// Stop with `VMError::ArithmeticOverflow`
+    Checked,
+}
+
+/// Where a `VM` stopped after `VM::run_with_budget`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Ran out of budget, call `run_with_budget` again to resume
+    Paused,
+    Halted,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct VM<'a> {
     pub instructions: Vec<u8>,
-    pub instruction_index: u8,
+    /// Read-only data section
+    pub data: Vec<u8>,
+    /// Shared memory for globals, addressed by `load_global` and friends
+    pub heap: Vec<u8>,
+    pub instruction_index: u32,
     pub current_instruction: Instruction,
     pub stack: Vec<u8>,
     pub frames: Frames,
     pub devices: DeviceManager<'a>,
+    pub arithmetic: Arithmetic,
+    /// Set when a wrapping instruction overflows, until read by `Instruction::Overflow`
+    pub overflow: bool,
+    /// Every instruction run is recorded here when set
+    pub trace: Option<Trace<'a>>,
 }
 
 impl Default for VM<'_> {
     fn default() -> Self {
         Self {
             instructions: vec![Instruction::Halt as u8],
+            data: vec![],
+            heap: vec![],
             instruction_index: 0,
             current_instruction: Instruction::Halt,
             stack: vec![],
             frames: Default::default(),
             devices: Default::default(),
+            arithmetic: Default::default(),
+            overflow: false,
+            trace: None,
         }
     }
 }
 
 impl<'a> VM<'a> {
-    pub fn new(instructions: Vec<u8>) -> Self {
+    pub fn new(program: Program) -> Self {
         Self {
-            instructions,
+            instructions: program.code,
+            data: program.data,
+            heap: vec![0; program.heap_size as usize],
             ..Default::default()
         }
     }
 
-    pub fn add_device<T: Device + 'a>(&mut self, device: &'a mut T) {
-        self.devices.add(device);
+    /// Attach a device under the lowest free ID, returning that ID
+    pub fn add_device<T: Device + 'a>(&mut self, device: T) -> u8 {
+        self.devices.add(device)
     }
 
     pub fn run(&mut self) -> Result<()> {
@@ -51,6 +93,28 @@ impl<'a> VM<'a> {
         Ok(())
     }
 
+    /// Whether the VM is stopped at a `halt`, which it can never run past
+    pub fn halted(&self) -> bool {
+        self.instructions.get(self.instruction_index as usize) == Some(&(Instruction::Halt as u8))
+    }
+
+    /// Run at most `budget` instructions, so the host can interleave other work
+    ///
+    /// A VM that's already halted stays `Halted`, even with a budget of zero.
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<Status> {
+        if self.halted() {
+            return Ok(Status::Halted);
+        }
+
+        for _ in 0..budget {
+            if self.step()? {
+                return Ok(Status::Halted);
+            }
+        }
+
+        Ok(Status::Paused)
+    }
+
     fn pop(&mut self) -> Result<u8> {
         self.stack.pop().ok_or(VMError::EmptyStack(
             self.current_instruction,
@@ -59,13 +123,23 @@ impl<'a> VM<'a> {
     }
 
     fn pop_u32(&mut self) -> Result<u32> {
-        // They're stored in le byte order, but because popping is reversing them, we can cheese it by loading as if they were be byte order
-        Ok(u32::from_be_bytes([
-            self.pop()?,
-            self.pop()?,
-            self.pop()?,
-            self.pop()?,
-        ]))
+        self.pop_int()
+    }
+
+    /// Pop an integer stored in le byte order, so its last byte is on top of the stack
+    fn pop_int<T: Integer>(&mut self) -> Result<T> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(T::SIZE)
+            .ok_or(VMError::EmptyStack(
+                self.current_instruction,
+                self.instruction_index,
+            ))?;
+
+        let value = T::from_le_slice(&self.stack[start..]);
+        self.stack.truncate(start);
+        Ok(value)
     }
 
     fn unary_op(&mut self, body: impl FnOnce(u8) -> u8) -> Result<()> {
@@ -83,4 +157,82 @@ impl<'a> VM<'a> {
         self.stack.push(body(a, b));
         Ok(())
     }
+
+    /// Run an `overflowing_*` integer method, handling overflow according to `self.arithmetic`
+    fn int_op<T, F>(&mut self, body: F) -> Result<()>
+    where
+        T: Integer,
+        F: FnOnce(T, T) -> (T, bool),
+    {
+        let b = self.pop_int()?;
+        let a = self.pop_int()?;
+
+        let (value, overflowed) = body(a, b);
+        if overflowed {
+            match self.arithmetic {
+                Arithmetic::Wrapping => self.overflow = true,
+                Arithmetic::Checked => {
+                    return Err(VMError::ArithmeticOverflow(
+                        self.current_instruction,
+                        self.instruction_index,
+                    ))
+                }
+            }
+        }
+
+        value.push_le(&mut self.stack);
+        Ok(())
+    }
+
+    fn div_op<T: Integer>(&mut self) -> Result<()> {
+        let len = self.stack.len();
+        if len >= T::SIZE && T::from_le_slice(&self.stack[len - T::SIZE..]) == T::ZERO {
+            return Err(VMError::DivideByZero(
+                self.current_instruction,
+                self.instruction_index,
+            ));
+        }
+
+        self.int_op(T::overflowing_div)
+    }
+
+    /// Compare two integers, pushing a single byte bool
+    fn compare<T, F>(&mut self, body: F) -> Result<()>
+    where
+        T: Integer,
+        F: FnOnce(T, T) -> bool,
+    {
+        let b = self.pop_int()?;
+        let a = self.pop_int()?;
+        self.stack.push(body(a, b) as u8);
+        Ok(())
+    }
+
+    fn current_frame(&self) -> Result<&Frame> {
+        self.frames.current().ok_or(VMError::ExpectedFrame(
+            self.current_instruction,
+            self.instruction_index,
+        ))
+    }
+
+    fn heap_index(&self, address: u32) -> Result<usize> {
+        if address as usize >= self.heap.len() {
+            return Err(VMError::HeapIndexOutOfBounds(
+                address,
+                self.instruction_index,
+            ));
+        }
+
+        Ok(address as usize)
+    }
+
+    /// Attribute a device fault to the current instruction
+    fn device_error(&self, error: DeviceError) -> VMError {
+        let (instruction, index) = (self.current_instruction, self.instruction_index);
+
+        match error {
+            DeviceError::NoSuchDevice(id) => VMError::NoSuchDevice(instruction, index, id),
+            error => VMError::Device(instruction, index, self.devices.selected(), error),
+        }
+    }
 }
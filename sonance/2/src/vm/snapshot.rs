@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    program::{write_bytes, Reader},
+    vm::{error::SnapshotError, Arithmetic, Frame, Frames, Instruction, VM},
+};
+
+pub const MAGIC: [u8; 4] = *b"SONS";
+pub const VERSION: u16 = 1;
+
+/// Everything needed to put a `VM` back exactly where it was, from `VM::snapshot`
+///
+/// Traces aren't included, and devices only include what their `Device::snapshot` returns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub instructions: Vec<u8>,
+    pub data: Vec<u8>,
+    pub heap: Vec<u8>,
+    pub instruction_index: u32,
+    pub current_instruction: Instruction,
+    pub stack: Vec<u8>,
+    pub frames: Frames,
+    pub arithmetic: Arithmetic,
+    pub overflow: bool,
+    pub selected_device: u8,
+    /// Each device's `Device::snapshot`, by ID
+    pub devices: BTreeMap<u8, Vec<u8>>,
+}
+
+impl VM<'_> {
+    pub fn snapshot(&self) -> Snapshot {
+        let (selected_device, devices) = self.devices.snapshot();
+
+        Snapshot {
+            instructions: self.instructions.clone(),
+            data: self.data.clone(),
+            heap: self.heap.clone(),
+            instruction_index: self.instruction_index,
+            current_instruction: self.current_instruction,
+            stack: self.stack.clone(),
+            frames: self.frames.clone(),
+            arithmetic: self.arithmetic,
+            overflow: self.overflow,
+            selected_device,
+            devices,
+        }
+    }
+
+    /// Restore a snapshot, which must have been taken with the same device IDs attached
+    ///
+    /// Nothing is changed if it fails.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        self.devices
+            .restore(snapshot.selected_device, &snapshot.devices)?;
+
+        self.instructions = snapshot.instructions.clone();
+        self.data = snapshot.data.clone();
+        self.heap = snapshot.heap.clone();
+        self.instruction_index = snapshot.instruction_index;
+        self.current_instruction = snapshot.current_instruction;
+        self.stack = snapshot.stack.clone();
+        self.frames = snapshot.frames.clone();
+        self.arithmetic = snapshot.arithmetic;
+        self.overflow = snapshot.overflow;
+
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// Encoded as the magic bytes and a `u16` version, followed by each field in order.
+    /// Lists are prefixed by their length as a `u32`, and all integers are little endian.
+    /// Frames are each a return index, base, stack base, arity and whether they're reserved,
+    /// followed by the slots of every frame.
+    /// Devices are each an ID byte followed by their snapshot.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = MAGIC.to_vec();
+        output.extend(VERSION.to_le_bytes());
+
+        write_bytes(&mut output, &self.instructions);
+        write_bytes(&mut output, &self.data);
+        write_bytes(&mut output, &self.heap);
+        output.extend(self.instruction_index.to_le_bytes());
+        output.push(self.current_instruction as u8);
+        write_bytes(&mut output, &self.stack);
+
+        output.extend((self.frames.depth() as u32).to_le_bytes());
+        for (frame, _) in self.frames.iter() {
+            output.extend(frame.return_index.to_le_bytes());
+            output.extend(frame.base.to_le_bytes());
+            output.extend(frame.stack_base.to_le_bytes());
+            output.push(frame.arity);
+            output.push(frame.reserved as u8);
+        }
+        write_bytes(&mut output, self.frames.slots());
+
+        output.push(match self.arithmetic {
+            Arithmetic::Wrapping => 0,
+            Arithmetic::Checked => 1,
+        });
+        output.push(self.overflow as u8);
+
+        output.push(self.selected_device);
+        output.extend((self.devices.len() as u32).to_le_bytes());
+        for (id, device) in &self.devices {
+            output.push(*id);
+            write_bytes(&mut output, device);
+        }
+
+        output
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader(bytes);
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let instructions = reader.bytes()?.to_vec();
+        let data = reader.bytes()?.to_vec();
+        let heap = reader.bytes()?.to_vec();
+        let instruction_index = reader.u32()?;
+        let code = reader.u8()?;
+        let current_instruction =
+            Instruction::try_from(code).map_err(|_| SnapshotError::InvalidInstruction(code))?;
+        let stack = reader.bytes()?.to_vec();
+
+        let mut frames = vec![];
+        for _ in 0..reader.u32()? {
+            frames.push(Frame {
+                return_index: reader.u32()?,
+                base: reader.u32()?,
+                stack_base: reader.u32()?,
+                arity: reader.u8()?,
+                reserved: reader.u8()? != 0,
+            });
+        }
+        let frames =
+            Frames::new(frames, reader.bytes()?.to_vec()).ok_or(SnapshotError::InvalidFrames)?;
+
+        let arithmetic = match reader.u8()? {
+            0 => Arithmetic::Wrapping,
+            1 => Arithmetic::Checked,
+            arithmetic => return Err(SnapshotError::InvalidArithmetic(arithmetic)),
+        };
+        let overflow = reader.u8()? != 0;
+
+        let selected_device = reader.u8()?;
+        let mut devices = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            devices.insert(reader.u8()?, reader.bytes()?.to_vec());
+        }
+
+        Ok(Self {
+            instructions,
+            data,
+            heap,
+            instruction_index,
+            current_instruction,
+            stack,
+            frames,
+            arithmetic,
+            overflow,
+            selected_device,
+            devices,
+        })
+    }
+}
@@ -1,6 +1,6 @@
 use crate::vm::{
     error::{Result, VMError},
-    Instruction, VM,
+    Instruction, TraceEvent, VM,
 };
 
 impl VM<'_> {
@@ -15,10 +15,13 @@ impl VM<'_> {
             instruction.map_err(|_| VMError::InvalidInstruction(code, self.instruction_index))?
         };
 
-        // eprintln!(
-        //     "{: >3}  {}",
-        //     self.instruction_index, self.current_instruction,
-        // );
+        if let Some(trace) = &mut self.trace {
+            trace.record(TraceEvent {
+                address: self.instruction_index,
+                instruction: self.current_instruction,
+                stack_depth: self.stack.len(),
+            });
+        }
 
         match self.current_instruction {
             Instruction::Halt => {
@@ -77,67 +80,180 @@ impl VM<'_> {
             }
 
             Instruction::Jump => {
-                let index = self.pop()?;
+                let index = self.pop_u32()?;
 
                 self.instruction_index = index;
+                return Ok(false);
             }
             Instruction::JumpIf => {
-                let index = self.pop()?;
+                let index = self.pop_u32()?;
                 let a = self.pop()?;
                 if a == 0 {
                     self.instruction_index = index;
+                    return Ok(false);
                 }
             }
 
             Instruction::Load => {
-                let variable = self.pop()?;
-                let a = self.frames.load(variable).ok_or(VMError::ExpectedFrame(
+                let variable = self.pop_int()?;
+                self.current_frame()?;
+                let a = self.frames.load(variable).ok_or(VMError::SlotOutOfBounds(
                     self.current_instruction,
                     self.instruction_index,
+                    variable,
                 ))?;
                 self.stack.push(a);
             }
             Instruction::Store => {
-                let variable = self.pop()?;
+                let variable = self.pop_int()?;
                 let a = self.pop()?;
+                self.current_frame()?;
                 self.frames
                     .store(variable, a)
-                    .ok_or(VMError::ExpectedFrame(
+                    .ok_or(VMError::SlotOutOfBounds(
                         self.current_instruction,
                         self.instruction_index,
+                        variable,
                     ))?;
             }
 
+            Instruction::Reserve => {
+                let count: u16 = self.pop_int()?;
+                let arity = self.current_frame()?.arity;
+                if count < arity.into() {
+                    return Err(VMError::ArityMismatch(
+                        self.current_instruction,
+                        self.instruction_index,
+                        arity,
+                        count.into(),
+                    ));
+                }
+
+                self.frames.reserve(count).ok_or(VMError::ExpectedFrame(
+                    self.current_instruction,
+                    self.instruction_index,
+                ))?;
+            }
+
+            Instruction::LoadGlobal => {
+                let address = self.pop_u32()?;
+                let value = self.heap[self.heap_index(address)?];
+                self.stack.push(value);
+            }
+            Instruction::StoreGlobal => {
+                let address = self.pop_u32()?;
+                let value = self.pop()?;
+                let index = self.heap_index(address)?;
+                self.heap[index] = value;
+            }
+            Instruction::LoadIndirect => {
+                let base = self.pop_u32()?;
+                let offset = self.pop_u32()?;
+                let value = self.heap[self.heap_index(base.saturating_add(offset))?];
+                self.stack.push(value);
+            }
+            Instruction::StoreIndirect => {
+                let base = self.pop_u32()?;
+                let offset = self.pop_u32()?;
+                let value = self.pop()?;
+                let index = self.heap_index(base.saturating_add(offset))?;
+                self.heap[index] = value;
+            }
+
+            Instruction::LoadData => {
+                let offset = self.pop_u32()?;
+                let value =
+                    *self
+                        .data
+                        .get(offset as usize)
+                        .ok_or(VMError::DataIndexOutOfBounds(
+                            offset,
+                            self.instruction_index,
+                        ))?;
+                self.stack.push(value);
+            }
+
             Instruction::Read => {
                 let index = self.pop_u32()?;
-                let value = self.devices.read(index);
+                let value = self
+                    .devices
+                    .read(index)
+                    .map_err(|error| self.device_error(error))?;
                 self.stack.push(value)
             }
             Instruction::Write => {
                 let index = self.pop_u32()?;
                 let value = self.pop()?;
-                self.devices.write(index, value);
+                self.devices
+                    .write(index, value)
+                    .map_err(|error| self.device_error(error))?;
+            }
+
+            Instruction::QueryDevice => {
+                let id = self.pop()?;
+                self.stack.push(self.devices.kind(id));
             }
 
             Instruction::Call => {
-                let index = self.pop()?;
-                self.frames.call(self.instruction_index);
+                let index = self.pop_u32()?;
+                let arity = self.pop()?;
+                let start =
+                    self.stack
+                        .len()
+                        .checked_sub(arity.into())
+                        .ok_or(VMError::ArityMismatch(
+                            self.current_instruction,
+                            self.instruction_index,
+                            arity,
+                            self.stack.len() as u32,
+                        ))?;
+
+                // Popped in order, so the top of the stack becomes the first slot
+                let mut args = self.stack.split_off(start);
+                args.reverse();
+                self.frames
+                    .call(self.instruction_index + 1, self.stack.len() as u32, &args);
 
                 self.instruction_index = index;
+                return Ok(false);
             }
             Instruction::Return => {
-                let index = self
+                let count = self.pop()?;
+                if self.frames.depth() <= 1 {
+                    return Err(VMError::TopLevelReturn(self.instruction_index));
+                }
+                let stack_base = self.current_frame()?.stack_base as usize;
+
+                let available = self.stack.len().saturating_sub(stack_base);
+                if available < count.into() {
+                    return Err(VMError::ArityMismatch(
+                        self.current_instruction,
+                        self.instruction_index,
+                        count,
+                        available as u32,
+                    ));
+                }
+
+                // Drop whatever the callee left below its return values
+                self.stack
+                    .drain(stack_base.min(self.stack.len())..self.stack.len() - count as usize);
+
+                let frame = self
                     .frames
                     .ret()
                     .ok_or(VMError::TopLevelReturn(self.instruction_index))?;
-
-                self.instruction_index = index;
+                self.instruction_index = frame.return_index;
+                return Ok(false);
             }
 
-            Instruction::Add => self.binary_op(|a, b| a + b)?,
-            Instruction::Sub => self.binary_op(|a, b| a - b)?,
-            Instruction::Mul => self.binary_op(|a, b| a * b)?,
-            Instruction::Div => self.binary_op(|a, b| a / b)?,
+            Instruction::Add => self.int_op(u8::overflowing_add)?,
+            Instruction::Sub => self.int_op(u8::overflowing_sub)?,
+            Instruction::Mul => self.int_op(u8::overflowing_mul)?,
+            Instruction::Div => self.div_op::<u8>()?,
+            Instruction::Overflow => {
+                self.stack.push(self.overflow as u8);
+                self.overflow = false;
+            }
 
             Instruction::BitAnd => self.binary_op(|a, b| a & b)?,
             Instruction::BitOr => self.binary_op(|a, b| a | b)?,
@@ -150,6 +266,58 @@ impl VM<'_> {
             Instruction::Eq => self.binary_op(|a, b| (a == b) as _)?,
             Instruction::Gt => self.binary_op(|a, b| (a > b) as _)?,
             Instruction::Geq => self.binary_op(|a, b| (a >= b) as _)?,
+
+            Instruction::AddU16 => self.int_op(u16::overflowing_add)?,
+            Instruction::SubU16 => self.int_op(u16::overflowing_sub)?,
+            Instruction::MulU16 => self.int_op(u16::overflowing_mul)?,
+            Instruction::DivU16 => self.div_op::<u16>()?,
+            Instruction::EqU16 => self.compare(|a: u16, b| a == b)?,
+            Instruction::GtU16 => self.compare(|a: u16, b| a > b)?,
+            Instruction::GeqU16 => self.compare(|a: u16, b| a >= b)?,
+
+            Instruction::AddU32 => self.int_op(u32::overflowing_add)?,
+            Instruction::SubU32 => self.int_op(u32::overflowing_sub)?,
+            Instruction::MulU32 => self.int_op(u32::overflowing_mul)?,
+            Instruction::DivU32 => self.div_op::<u32>()?,
+            Instruction::EqU32 => self.compare(|a: u32, b| a == b)?,
+            Instruction::GtU32 => self.compare(|a: u32, b| a > b)?,
+            Instruction::GeqU32 => self.compare(|a: u32, b| a >= b)?,
+
+            Instruction::AddU64 => self.int_op(u64::overflowing_add)?,
+            Instruction::SubU64 => self.int_op(u64::overflowing_sub)?,
+            Instruction::MulU64 => self.int_op(u64::overflowing_mul)?,
+            Instruction::DivU64 => self.div_op::<u64>()?,
+            Instruction::EqU64 => self.compare(|a: u64, b| a == b)?,
+            Instruction::GtU64 => self.compare(|a: u64, b| a > b)?,
+            Instruction::GeqU64 => self.compare(|a: u64, b| a >= b)?,
+
+            Instruction::AddI8 => self.int_op(i8::overflowing_add)?,
+            Instruction::SubI8 => self.int_op(i8::overflowing_sub)?,
+            Instruction::MulI8 => self.int_op(i8::overflowing_mul)?,
+            Instruction::DivI8 => self.div_op::<i8>()?,
+            Instruction::GtI8 => self.compare(|a: i8, b| a > b)?,
+            Instruction::GeqI8 => self.compare(|a: i8, b| a >= b)?,
+
+            Instruction::AddI16 => self.int_op(i16::overflowing_add)?,
+            Instruction::SubI16 => self.int_op(i16::overflowing_sub)?,
+            Instruction::MulI16 => self.int_op(i16::overflowing_mul)?,
+            Instruction::DivI16 => self.div_op::<i16>()?,
+            Instruction::GtI16 => self.compare(|a: i16, b| a > b)?,
+            Instruction::GeqI16 => self.compare(|a: i16, b| a >= b)?,
+
+            Instruction::AddI32 => self.int_op(i32::overflowing_add)?,
+            Instruction::SubI32 => self.int_op(i32::overflowing_sub)?,
+            Instruction::MulI32 => self.int_op(i32::overflowing_mul)?,
+            Instruction::DivI32 => self.div_op::<i32>()?,
+            Instruction::GtI32 => self.compare(|a: i32, b| a > b)?,
+            Instruction::GeqI32 => self.compare(|a: i32, b| a >= b)?,
+
+            Instruction::AddI64 => self.int_op(i64::overflowing_add)?,
+            Instruction::SubI64 => self.int_op(i64::overflowing_sub)?,
+            Instruction::MulI64 => self.int_op(i64::overflowing_mul)?,
+            Instruction::DivI64 => self.div_op::<i64>()?,
+            Instruction::GtI64 => self.compare(|a: i64, b| a > b)?,
+            Instruction::GeqI64 => self.compare(|a: i64, b| a >= b)?,
         }
 
         self.instruction_index += 1;
@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    io::{self, Result, Write},
+};
+
+use crate::vm::Instruction;
+
+/// A single executed instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEvent {
+    pub address: u32,
+    pub instruction: Instruction,
+    /// Stack length in bytes before the instruction ran
+    pub stack_depth: usize,
+}
+
+/// Opt-in record of everything a `VM` runs, set with `VM::trace`
+///
+/// Counts are always kept, while events are only written out as they happen when created with `Trace::log`.
+#[derive(Default)]
+pub struct Trace<'a> {
+    log: Option<Box<dyn Write + 'a>>,
+    /// First error from writing to `log`, after which nothing more is written until `flush` reports it
+    error: Option<io::Error>,
+    counts: HashMap<u32, (Instruction, u64)>,
+}
+
+impl<'a> Trace<'a> {
+    /// Write a line to `output` for every event, as it happens
+    pub fn log(output: impl Write + 'a) -> Self {
+        Self {
+            log: Some(Box::new(output)),
+            ..Default::default()
+        }
+    }
+
+    /// Only keep counts, for `report`
+    pub fn counts() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, event: TraceEvent) {
+        self.counts
+            .entry(event.address)
+            .or_insert((event.instruction, 0))
+            .1 += 1;
+
+        if let (Some(log), None) = (&mut self.log, &self.error) {
+            self.error = write_event(log, &event).err();
+        }
+    }
+
+    /// Flush the log, returning the first error from writing to it if there was one
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        match &mut self.log {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Address, instruction and count of everything that ran, most run first
+    pub fn addresses(&self) -> Vec<(u32, Instruction, u64)> {
+        let mut addresses: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(address, (instruction, count))| (*address, *instruction, *count))
+            .collect();
+
+        addresses.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        addresses
+    }
+
+    /// Total count of each kind of instruction, most run first
+    pub fn instructions(&self) -> Vec<(Instruction, u64)> {
+        let mut totals: HashMap<Instruction, u64> = HashMap::new();
+        for (instruction, count) in self.counts.values() {
+            *totals.entry(*instruction).or_default() += count;
+        }
+
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as u8).cmp(&(b.0 as u8))));
+        totals
+    }
+
+    /// Total count of instructions between each label and the next, most run first
+    ///
+    /// Anything before the first label is counted under an empty name.
+    pub fn labels(&self, labels: &BTreeMap<String, u32>) -> Vec<(String, u64)> {
+        let starts: BTreeMap<u32, &str> = labels
+            .iter()
+            .map(|(name, address)| (*address, name.as_str()))
+            .collect();
+
+        let mut totals: HashMap<&str, u64> = HashMap::new();
+        for (address, (_, count)) in &self.counts {
+            let name = starts
+                .range(..=address)
+                .next_back()
+                .map_or("", |(_, name)| name);
+            *totals.entry(name).or_default() += count;
+        }
+
+        let mut totals: Vec<_> = totals
+            .into_iter()
+            .map(|(name, count)| (name.to_owned(), count))
+            .collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        totals
+    }
+
+    /// A printable summary of counts by label, instruction and address
+    pub fn report<'b>(&'b self, labels: &'b BTreeMap<String, u32>) -> Report<'b> {
+        Report {
+            trace: self,
+            labels,
+        }
+    }
+}
+
+pub struct Report<'a> {
+    trace: &'a Trace<'a>,
+    labels: &'a BTreeMap<String, u32>,
+}
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        writeln!(f, "{:>12}  label", "count")?;
+        for (name, count) in self.trace.labels(self.labels) {
+            match name.as_str() {
+                "" => writeln!(f, "{count:>12}  (start)")?,
+                name => writeln!(f, "{count:>12}  #{name}")?,
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>12}  instruction", "count")?;
+        for (instruction, count) in self.trace.instructions() {
+            writeln!(f, "{count:>12}  {instruction}")?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>12} {:>8}  instruction", "count", "address")?;
+        for (address, instruction, count) in self.trace.addresses() {
+            writeln!(f, "{count:>12} {address:>8}  {instruction}")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_event(output: &mut impl Write, event: &TraceEvent) -> Result<()> {
+    writeln!(
+        output,
+        "{:>6} {:>6}  {}",
+        event.address, event.stack_depth, event.instruction
+    )
+}
+
+impl Debug for Trace<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Trace")
+            .field("log", &self.log.is_some())
+            .field("counts", &self.counts)
+            .finish()
+    }
+}
+
+/// Clones only keep the counts, since the log can't be shared
+impl Clone for Trace<'_> {
+    fn clone(&self) -> Self {
+        Self {
+            counts: self.counts.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl PartialEq for Trace<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.counts == other.counts
+    }
+}